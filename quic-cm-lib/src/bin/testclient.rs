@@ -1,3 +1,5 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
 use quic_cm::QuicClient;
 
 
@@ -6,9 +8,9 @@ async fn main() {
     env_logger::builder().format_timestamp_nanos().init();
     let mut client = QuicClient::connect("127.0.0.1:7878", "quiccat").await.unwrap();
     let bytes = *b"ABCDEF\n";
-    client.write(&bytes).await.unwrap();
+    client.write_all(&bytes).await.unwrap();
 
     let mut incoming: [u8; 10000] = [0; 10000];
-    client.read(&mut incoming).await.unwrap();
-    println!("Received: {}", String::from_utf8(incoming.to_vec()).unwrap())
+    let n = client.read(&mut incoming).await.unwrap();
+    println!("Received: {}", String::from_utf8_lossy(&incoming[..n]))
 }
@@ -0,0 +1,262 @@
+//! Blocking client API, for applications that do not use tokio.
+//!
+//! [`QuicClient`] in this module has the same semantics as the async client,
+//! but operates on [`std::os::unix::net::UnixStream`] and implements
+//! [`std::io::Read`] and [`std::io::Write`].
+
+use std::{
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+};
+
+use crate::{
+    ConnectOptions,
+    Error,
+    Result,
+    common::control_socket_path,
+    common::codec::{ConnectionStats, Frame},
+    receiver::{Receiver, connect_message, data_message, fin_message},
+};
+
+
+/// Represents a client QUIC connections from an application.
+///
+/// Shutting down the writer with [`QuicClient::shutdown_write`] sends FIN on the
+/// QUIC stream, while the receiving direction stays open. When the peer
+/// finishes its sending direction, reads return end of file. Dropping the
+/// client without shutting down the writer first aborts the stream, and
+/// manager sends RESET_STREAM to the peer.
+///
+/// [`QuicClient::reset`] and [`QuicClient::stop_reading`] abort one direction
+/// of the stream explicitly, with an application error code. When the peer
+/// aborts the stream, reads fail with [`Error::StreamReset`] and writes with
+/// [`Error::StreamStopped`], carried inside the returned [`io::Error`].
+///
+/// Writes complete when the data is passed to the manager, as long as the
+/// manager has advertised enough credit for it. When the credit runs out,
+/// writing blocks until the manager has passed earlier data to the QUIC stream.
+pub struct QuicClient {
+    socket: UnixStream,
+    receiver: Receiver,
+    stream_id: u64,
+}
+
+impl QuicClient {
+
+    /// Initiate QUIC connection to given address, and open one bidirectional
+    /// stream on it.
+    ///
+    /// Address string is of form `<address>:<port>`. Address can be IP address or
+    /// DNS name. Port can be omitted, in which case default port 7878 is used.
+    /// `app_proto` specifies the application protocol given in QUIC configuration.
+    /// Server must have the same protocol identifier configured.
+    pub fn connect(address: &str, app_proto: &str) -> Result<QuicClient> {
+        Self::open(address, app_proto, &ConnectOptions::default(), true)
+    }
+
+
+    /// Initiate QUIC connection to given address with `options`, and open one
+    /// bidirectional stream on it. Address and `app_proto` are as in
+    /// [`QuicClient::connect`].
+    pub fn connect_with(
+        address: &str,
+        app_proto: &str,
+        options: &ConnectOptions,
+    ) -> Result<QuicClient> {
+        Self::open(address, app_proto, options, true)
+    }
+
+
+    /// Initiate QUIC connection to given address, and open one unidirectional
+    /// stream on it for sending. Reads from the client return end of file.
+    /// Address and `app_proto` are as in [`QuicClient::connect`].
+    pub fn connect_uni(address: &str, app_proto: &str) -> Result<QuicClient> {
+        Self::open(address, app_proto, &ConnectOptions::default(), false)
+    }
+
+
+    fn open(
+        address: &str,
+        app_proto: &str,
+        options: &ConnectOptions,
+        bidi: bool,
+    ) -> Result<QuicClient> {
+        let mut v = connect_message(address, app_proto, options)?;
+        Frame::Open { bidi }.encode(&mut v);
+        let socket = match UnixStream::connect(control_socket_path()) {
+            Ok(s) => s,
+            Err(e) => return Err(Error::ManagerUnreachable(e)),
+        };
+        let mut client = QuicClient {
+            socket,
+            receiver: Receiver::new(),
+            stream_id: 0,
+        };
+
+        if let Err(e) = client.socket.write_all(&v) {
+            return Err(Error::Io(e));
+        }
+        debug!("fifo connect, wrote HELLO, CONNECT and OPEN with {} bytes", v.len());
+
+        loop {
+            if client.receiver.is_connected() {
+                if let Some(stream_id) = client.receiver.take_opened(bidi) {
+                    client.stream_id = stream_id;
+                    return Ok(client);
+                }
+            }
+            if client.fill()? == 0 {
+                return Err(Receiver::closed_error().into());
+            }
+        }
+    }
+
+
+    /// Returns the QUIC stream ID.
+    pub fn stream_id(&self) -> u64 {
+        self.stream_id
+    }
+
+
+    /// Returns None if the client was not connected in 0-RTT. Otherwise waits
+    /// until the handshake completes, and returns true if the server accepted
+    /// the early data, or false if it was retransmitted after the handshake,
+    /// or its acceptance could not be confirmed. Manager reports acceptance
+    /// when no stream data is retransmitted shortly after the handshake.
+    /// See [`crate::ConnectOptions::early_data`].
+    pub fn early_data_accepted(&mut self) -> Result<Option<bool>> {
+        loop {
+            if let Some(status) = self.receiver.early_data_status() {
+                return Ok(status);
+            }
+            if self.fill()? == 0 {
+                return Err(Receiver::closed_error().into());
+            }
+        }
+    }
+
+
+    /// Congestion control algorithm of the QUIC connection. It differs from
+    /// the one asked with [`crate::ConnectOptions::congestion_control`] when
+    /// the client joined an existing connection. None if manager did not tell.
+    pub fn congestion_control(&self) -> Option<&str> {
+        self.receiver.congestion_control()
+    }
+
+
+    /// Get statistics of the QUIC connection used by this client.
+    pub fn stats(&mut self) -> Result<ConnectionStats> {
+        self.socket.write_all(&Frame::StatsRequest.to_bytes())?;
+        loop {
+            if let Some(stats) = self.receiver.take_stats() {
+                return Ok(stats);
+            }
+            if self.fill()? == 0 {
+                return Err(Receiver::closed_error().into());
+            }
+        }
+    }
+
+
+    /// Send FIN on the QUIC stream, after data written earlier. Data can still
+    /// be read after this.
+    pub fn shutdown_write(&mut self) -> Result<()> {
+        if self.receiver.close_write(self.stream_id) {
+            self.send(&fin_message(self.stream_id))?;
+        }
+        Ok(())
+    }
+
+
+    /// Abort sending on the QUIC stream. Manager resets the stream with
+    /// application error `code`, and data not yet delivered to the peer may be
+    /// discarded. Data can still be read after this.
+    pub fn reset(&mut self, code: u64) -> Result<()> {
+        if !self.receiver.close_write(self.stream_id) {
+            return Ok(());
+        }
+        Ok(self.send(&Frame::Reset { stream_id: self.stream_id, code }.to_bytes())?)
+    }
+
+
+    /// Ask the peer to stop sending on the QUIC stream, with application error
+    /// `code`. Data that is not yet read is discarded, and further reads return
+    /// end of file.
+    pub fn stop_reading(&mut self, code: u64) -> Result<()> {
+        if !self.receiver.close_read(self.stream_id) {
+            return Ok(());
+        }
+        Ok(self.send(&Frame::StopSending { stream_id: self.stream_id, code }.to_bytes())?)
+    }
+
+
+    /// Read more bytes from Unix socket and parse them. Returns number of bytes
+    /// read, 0 means that manager has closed the socket.
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut buf = [0; 16384];
+        let n = self.socket.read(&mut buf)?;
+        self.receiver.feed(&buf[..n])?;
+        Ok(n)
+    }
+
+
+    /// Wait until manager has granted credit for sending data. Data received
+    /// meanwhile is buffered for later reads. Returns the number of bytes that
+    /// can be sent in one DATA frame.
+    fn wait_credit(&mut self) -> io::Result<usize> {
+        loop {
+            self.receiver.check_write(self.stream_id)?;
+            let credit = self.receiver.credit(self.stream_id);
+            if credit > 0 {
+                return Ok(credit);
+            }
+            if self.fill()? == 0 {
+                return Err(Receiver::closed_error());
+            }
+        }
+    }
+
+
+    /// Write message to Unix socket.
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        self.socket.write_all(msg)?;
+        debug!("Wrote to Unix socket {} bytes", msg.len());
+        Ok(())
+    }
+}
+
+
+impl Read for QuicClient {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let id = self.stream_id;
+        self.receiver.check_read(id)?;
+        while !self.receiver.has_data(id) && !self.receiver.is_eof(id) {
+            self.fill()?;
+            self.receiver.check_read(id)?;
+        }
+        let n = self.receiver.read_data(id, buf);
+        if let Some(bytes) = self.receiver.take_read_credit(id) {
+            self.send(&Frame::Credit { stream_id: id, bytes }.to_bytes())?;
+        }
+        Ok(n)
+    }
+}
+
+
+impl Write for QuicClient {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let credit = self.wait_credit()?;
+        let (msg, n) = data_message(self.stream_id, buf, credit);
+        self.receiver.use_credit(self.stream_id, n);
+        self.send(&msg)?;
+        Ok(n)
+    }
+
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
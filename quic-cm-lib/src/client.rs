@@ -0,0 +1,161 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    ConnectOptions,
+    QuicSession,
+    Result,
+    common::codec::ConnectionStats,
+    split::{OwnedReadHalf, OwnedWriteHalf},
+};
+
+
+/// Represents a bidirectional QUIC stream of a client application.
+///
+/// The client is a byte stream that implements tokio's [`AsyncRead`] and
+/// [`AsyncWrite`], so it can be used with [`tokio::io::copy`], `BufReader` and
+/// other utilities built on top of these traits.
+///
+/// Shutting down the writer with [`QuicClient::shutdown_write`] (or
+/// `AsyncWriteExt::shutdown`) sends FIN on the QUIC stream, while the receiving
+/// direction stays open. When the peer finishes its sending direction, reads
+/// return end of file. Dropping the client without shutting down the writer
+/// first aborts the stream, and manager sends RESET_STREAM to the peer.
+///
+/// [`QuicClient::reset`] and [`QuicClient::stop_reading`] abort one direction
+/// of the stream explicitly, with an application error code. When the peer
+/// aborts the stream, reads fail with [`Error::StreamReset`] and writes with
+/// [`Error::StreamStopped`], carried inside the returned [`io::Error`].
+///
+/// [`Error::StreamReset`]: crate::Error::StreamReset
+/// [`Error::StreamStopped`]: crate::Error::StreamStopped
+///
+/// Writes complete when the data is passed to the manager, as long as the
+/// manager has advertised enough credit for it. When the credit runs out,
+/// writing waits until the manager has passed earlier data to the QUIC stream.
+///
+/// The client can be split into halves with [`QuicClient::into_split`], to read
+/// and write concurrently from different tasks.
+pub struct QuicClient {
+    read: OwnedReadHalf,
+    write: OwnedWriteHalf,
+}
+
+impl QuicClient {
+
+    /// Initiate QUIC connection to given address, and open one bidirectional
+    /// stream on it. Use [`QuicSession`] to open more streams on the same
+    /// session.
+    ///
+    /// Address string is of form `<address>:<port>`. Address can be IP address or
+    /// DNS name. Port can be omitted, in which case default port 7878 is used.
+    /// `app_proto` specifies the application protocol given in QUIC configuration.
+    /// Server must have the same protocol identifier configured.
+    pub async fn connect(address: &str, app_proto: &str) -> Result<QuicClient> {
+        QuicSession::connect(address, app_proto).await?.open_bidi().await
+    }
+
+
+    /// Initiate QUIC connection to given address with `options`, and open one
+    /// bidirectional stream on it. Address and `app_proto` are as in
+    /// [`QuicClient::connect`].
+    pub async fn connect_with(
+        address: &str,
+        app_proto: &str,
+        options: &ConnectOptions,
+    ) -> Result<QuicClient> {
+        QuicSession::connect_with(address, app_proto, options).await?.open_bidi().await
+    }
+
+
+    pub(crate) fn from_halves(read: OwnedReadHalf, write: OwnedWriteHalf) -> QuicClient {
+        QuicClient { read, write }
+    }
+
+
+    /// Returns the QUIC stream ID.
+    pub fn stream_id(&self) -> u64 {
+        self.read.stream_id()
+    }
+
+
+    /// Split the client into read and write halves that can be used from
+    /// different tasks.
+    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        (self.read, self.write)
+    }
+
+
+    /// Borrow read and write halves of the client, to read and write
+    /// concurrently within one task.
+    pub fn split(&mut self) -> (&mut OwnedReadHalf, &mut OwnedWriteHalf) {
+        (&mut self.read, &mut self.write)
+    }
+
+
+    /// Send FIN on the QUIC stream, after data written earlier. Data can still
+    /// be read after this.
+    pub async fn shutdown_write(&mut self) -> Result<()> {
+        self.write.shutdown_write().await
+    }
+
+
+    /// Abort sending on the QUIC stream. Manager resets the stream with
+    /// application error `code`, and data not yet delivered to the peer may be
+    /// discarded. Data can still be read after this.
+    pub async fn reset(&mut self, code: u64) -> Result<()> {
+        self.write.reset(code).await
+    }
+
+
+    /// Ask the peer to stop sending on the QUIC stream, with application error
+    /// `code`. Data that is not yet read is discarded, and further reads return
+    /// end of file.
+    pub async fn stop_reading(&mut self, code: u64) -> Result<()> {
+        self.read.stop_reading(code).await
+    }
+
+
+    /// Get statistics of the QUIC connection used by this client.
+    pub async fn stats(&mut self) -> Result<ConnectionStats> {
+        self.write.stats().await
+    }
+}
+
+
+impl AsyncRead for QuicClient {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().read).poll_read(cx, buf)
+    }
+}
+
+
+impl AsyncWrite for QuicClient {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().write).poll_write(cx, buf)
+    }
+
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().write).poll_flush(cx)
+    }
+
+
+    /// Send FIN on the QUIC stream. Data can still be read after shutdown.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().write).poll_shutdown(cx)
+    }
+}
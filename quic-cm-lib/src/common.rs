@@ -1,30 +1,59 @@
-use std::{
-    io::{Result, Write},
-    os::unix::net,
-};
+/// Default path of the manager control socket.
+pub const QCM_CONTROL_SOCKET: &str = "/tmp/qcm-control";
 
-use tokio::net::UnixStream;
-use tokio::io::AsyncWriteExt;
+/// Returns path of the manager control socket: environment variable
+/// `QCM_CONTROL_SOCKET` if set, for manager that uses another socket path,
+/// otherwise the default.
+pub fn control_socket_path() -> String {
+    std::env::var("QCM_CONTROL_SOCKET").unwrap_or_else(|_| QCM_CONTROL_SOCKET.to_string())
+}
 
+/// Server port used when the destination address does not have one.
+pub const DEFAULT_PORT: u16 = 7878;
 
-pub const QCM_CONTROL_SOCKET: &str = "/tmp/qcm-control";
+/// Maximum number of payload bytes carried in a single DATA frame.
+pub const MAX_DATA_LEN: usize = 65535;
 
+/// Number of bytes that manager may deliver on a stream before the client
+/// application has read them. Client grants more with CREDIT frames as the
+/// application reads.
+pub const RECV_WINDOW: u64 = 1_000_000;
 
-/// Write DATA header to socket with number of data bytes.
-pub async fn write_data_header(socket: &mut UnixStream, length: u32) -> Result<usize> {
-    let mut header: [u8; 8] = [0; 8];
-    // write "DATA" type specified and u32 length information
-    header[..4].copy_from_slice("DATA".as_bytes());
-    header[4..].copy_from_slice(&length.to_be_bytes());
-    socket.write(&header).await
+/// Application error code that manager uses when it resets a stream because
+/// the client application left without finishing it.
+pub const CLIENT_GONE_ERROR_CODE: u64 = 0;
+
+/// Returns true if QUIC stream `stream_id` is bidirectional.
+pub fn is_bidi(stream_id: u64) -> bool {
+    stream_id & 0x2 == 0
 }
 
+/// Returns true if QUIC stream `stream_id` was opened by the server.
+pub fn is_server_initiated(stream_id: u64) -> bool {
+    stream_id & 0x1 == 1
+}
+
+/// Append `v` to `out` as QUIC variable-length integer (RFC 9000, section 16).
+/// Used for flow ID that prefixes datagrams. `v` must be less than 2^62.
+pub fn put_varint(out: &mut Vec<u8>, v: u64) {
+    match v {
+        0..=0x3f => out.push(v as u8),
+        0x40..=0x3fff => out.extend_from_slice(&(v as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => out.extend_from_slice(&(v as u32 | 0x8000_0000).to_be_bytes()),
+        _ => {
+            assert!(v < 1 << 62, "Too large varint {}", v);
+            out.extend_from_slice(&(v | 0xc000_0000_0000_0000).to_be_bytes());
+        },
+    }
+}
+
+/// Parse QUIC variable-length integer from start of `buf`. Returns the value
+/// and number of bytes it took, or None if `buf` is too short.
+pub fn get_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let len = 1 << (buf.first()? >> 6);
+    let bytes = buf.get(..len)?;
+    let v = bytes.iter().fold(0u64, |v, b| (v << 8) | *b as u64);
+    Some((v & (u64::MAX >> (64 - 8 * len + 2)), len))
+}
 
-// TODO: stupid temporary solution to make cm-manager work without tokio
-pub fn write_data_header_sync(socket: &mut net::UnixStream, length: u32) -> Result<usize> {
-    let mut header: [u8; 8] = [0; 8];
-    // write "DATA" type specified and u32 length information
-    header[..4].copy_from_slice("DATA".as_bytes());
-    header[4..].copy_from_slice(&length.to_be_bytes());
-    socket.write(&header)
-}
\ No newline at end of file
+pub mod codec;
@@ -0,0 +1,496 @@
+//! Framing protocol between QUIC-CM library and manager.
+//!
+//! Every frame starts with one byte frame type and u32 payload length in
+//! network byte order, followed by the payload. The client application starts
+//! by sending HELLO with the protocol version it speaks, followed by CONNECT.
+//! Manager answers with HELLO carrying the version it selected, and CONNECT_OK
+//! when the QUIC connection is ready. Until then, and after that, either side
+//! may send ERROR.
+//!
+//! One session on a Unix socket carries many QUIC streams. Client opens a
+//! stream with OPEN, and manager answers with OPENED carrying the QUIC stream
+//! ID. Client that wants to receive streams opened by the server sends ACCEPT
+//! for bidirectional or unidirectional streams, after which manager may give
+//! streams of that kind to it, announced with NEW_STREAM.
+//! Frames that concern a stream start with the stream ID.
+//!
+//! Client that accepts the replay risk of 0-RTT data says so in CONNECT. When
+//! manager resumes a TLS session, it may then answer with CONNECT_OK before
+//! the handshake is complete, and streams opened by the client carry early
+//! data. Manager tells with EARLY_DATA after the handshake completes, whether
+//! the server accepted the early data, or it had to be retransmitted.
+//!
+//! CONNECT may name a congestion control algorithm. Manager uses it if it
+//! opens a new connection for the client, and reports the algorithm in use in
+//! CONNECT_OK and STATS, so that a client joining an existing connection can
+//! tell that it got a different one.
+//!
+//! QUIC datagrams of the connection are shared by sessions using flow IDs. A
+//! session asks for a flow with DATAGRAM_OPEN, and manager assigns it a flow ID
+//! with DATAGRAM_OPENED. Manager prefixes the datagrams that the session sends
+//! in DATAGRAM frames with the flow ID as QUIC variable-length integer, and
+//! delivers datagrams from the server to the session that owns the flow in
+//! their prefix. Datagrams are unreliable, so manager drops them when a client
+//! or the connection cannot take more.
+//!
+//! Writes from client are limited by credit that manager advertises in CREDIT
+//! frames, similarly to QUIC flow control. Manager grants the initial window
+//! when the stream is opened, and more credit as it passes data to the QUIC
+//! stream.
+//! Client may send DATA frames up to the credit it has, without waiting for
+//! a reply to each of them. Other frames do not consume credit.
+//!
+//! In the other direction, manager delivers DATA frames of a stream up to the
+//! receive window of the client. The window starts at
+//! [`RECV_WINDOW`](crate::common::RECV_WINDOW) when the stream is opened, and
+//! client sends CREDIT frames to manager as the application reads the data.
+//! Data that the client has no room for stays in the QUIC stream, so that one
+//! stream that the application does not read does not hold up the others.
+//!
+//! [`FrameDecoder`] buffers incoming bytes, so frames can be split across
+//! several reads, and several frames can arrive in one read.
+//!
+//! Fields that are added to an existing frame are appended to the end of its
+//! payload, and the decoder treats missing trailing fields as absent. Peers
+//! that do not know a new field therefore still interoperate, and adding one
+//! does not change the protocol version. New frame types are only sent to
+//! peers that have asked for them, for example EARLY_DATA to clients that
+//! allowed early data in CONNECT. The version is raised when the meaning of
+//! existing frames changes.
+
+use std::time::Duration;
+
+use crate::{
+    Error,
+    Result,
+    common::MAX_DATA_LEN,
+};
+
+/// Protocol version implemented by this crate.
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// Oldest protocol version that is still accepted. Version 1 acknowledged
+/// every write separately, version 2 had one stream per session, and in
+/// version 3 manager delivered data without a receive window. They are not
+/// supported anymore.
+pub const MIN_PROTOCOL_VERSION: u16 = 4;
+
+/// Length of frame type and payload length fields.
+pub const FRAME_HEADER_LEN: usize = 5;
+
+/// Largest accepted frame payload.
+pub const MAX_FRAME_LEN: usize = MAX_DATA_LEN + 1024;
+
+/// Longest string that fits in a string field of a frame.
+pub const MAX_STRING_LEN: usize = u16::MAX as usize;
+
+const FRAME_HELLO: u8 = 0x01;
+const FRAME_CONNECT: u8 = 0x02;
+const FRAME_CONNECT_OK: u8 = 0x03;
+const FRAME_DATA: u8 = 0x04;
+const FRAME_CREDIT: u8 = 0x05;
+const FRAME_FIN: u8 = 0x06;
+const FRAME_RESET: u8 = 0x07;
+const FRAME_ERROR: u8 = 0x08;
+const FRAME_STATS_REQUEST: u8 = 0x09;
+const FRAME_STATS: u8 = 0x0a;
+const FRAME_STOP_SENDING: u8 = 0x0b;
+const FRAME_OPEN: u8 = 0x0c;
+const FRAME_OPENED: u8 = 0x0d;
+const FRAME_NEW_STREAM: u8 = 0x0e;
+const FRAME_ACCEPT: u8 = 0x0f;
+const FRAME_DATAGRAM_OPEN: u8 = 0x10;
+const FRAME_DATAGRAM_OPENED: u8 = 0x11;
+const FRAME_DATAGRAM: u8 = 0x12;
+const FRAME_EARLY_DATA: u8 = 0x13;
+
+
+/// Statistics of the QUIC connection that the stream belongs to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectionStats {
+    /// Smoothed round-trip time.
+    pub rtt: Duration,
+    /// Congestion window in bytes.
+    pub cwnd: u64,
+    /// Number of QUIC packets sent.
+    pub sent: u64,
+    /// Number of QUIC packets received.
+    pub recv: u64,
+    /// Number of QUIC packets lost.
+    pub lost: u64,
+    /// Number of bytes sent.
+    pub sent_bytes: u64,
+    /// Number of bytes received.
+    pub recv_bytes: u64,
+    /// Estimated delivery rate in bytes per second.
+    pub delivery_rate: u64,
+    /// Congestion control algorithm of the connection, for example "cubic".
+    /// Empty if manager did not report it.
+    pub congestion_control: String,
+}
+
+
+/// Frame exchanged between client application and manager.
+#[derive(Debug)]
+pub enum Frame {
+    /// Protocol version offered by client, or selected by manager.
+    Hello { version: u16 },
+    /// Request to connect to `address` using application protocol `app_proto`.
+    /// `server_name` is sent in TLS SNI and used to verify the server
+    /// certificate, instead of the host in `address`. `identity` names the
+    /// client certificate, configured in manager, that is used on the
+    /// connection. `early_data` allows sending data in 0-RTT.
+    /// `congestion_control` names the algorithm for a new connection.
+    Connect {
+        address: String,
+        app_proto: String,
+        server_name: Option<String>,
+        identity: Option<String>,
+        early_data: bool,
+        congestion_control: Option<String>,
+    },
+    /// QUIC connection is established and streams can be opened. With
+    /// `early_data`, the handshake is still in progress and data is sent in
+    /// 0-RTT. `congestion_control` is the algorithm of the connection.
+    ConnectOk { early_data: bool, congestion_control: Option<String> },
+    /// Handshake of a session connected in 0-RTT has completed. Early data was
+    /// either `accepted` by the server, or retransmitted or not confirmed.
+    EarlyData { accepted: bool },
+    /// Client asks to open bidirectional or unidirectional stream.
+    Open { bidi: bool },
+    /// Stream requested with OPEN is ready to be used.
+    Opened { stream_id: u64 },
+    /// Server has opened a stream.
+    NewStream { stream_id: u64 },
+    /// Client wants to receive bidirectional or unidirectional streams that
+    /// the server opens.
+    Accept { bidi: bool },
+    /// Stream data.
+    Data { stream_id: u64, data: Vec<u8> },
+    /// Receiver of the frame may send given number of bytes more in DATA
+    /// frames of the stream.
+    Credit { stream_id: u64, bytes: u64 },
+    /// Sender has finished sending on the stream.
+    Fin { stream_id: u64 },
+    /// Sender has abandoned the stream with given application error code.
+    Reset { stream_id: u64, code: u64 },
+    /// Receiver asks to stop sending on the stream, with given application
+    /// error code.
+    StopSending { stream_id: u64, code: u64 },
+    /// Client asks for a datagram flow.
+    DatagramOpen,
+    /// Datagrams of the session are carried in flow `flow_id`.
+    DatagramOpened { flow_id: u64 },
+    /// QUIC datagram payload, without the flow ID.
+    Datagram { data: Vec<u8> },
+    /// Error, see [`Error`] for details.
+    Error(Error),
+    /// Client asks for connection statistics.
+    StatsRequest,
+    /// Connection statistics sent by manager.
+    Stats(ConnectionStats),
+}
+
+impl Frame {
+
+    /// Append encoded frame to `out`.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(&[0; FRAME_HEADER_LEN]);
+        let ty = match self {
+            Frame::Hello { version } => {
+                out.extend_from_slice(&version.to_be_bytes());
+                FRAME_HELLO
+            },
+            Frame::Connect {
+                address, app_proto, server_name, identity, early_data, congestion_control,
+            } => {
+                put_string(out, address);
+                put_string(out, app_proto);
+                put_string(out, server_name.as_deref().unwrap_or(""));
+                put_string(out, identity.as_deref().unwrap_or(""));
+                out.push(*early_data as u8);
+                put_string(out, congestion_control.as_deref().unwrap_or(""));
+                FRAME_CONNECT
+            },
+            Frame::ConnectOk { early_data, congestion_control } => {
+                out.push(*early_data as u8);
+                put_string(out, congestion_control.as_deref().unwrap_or(""));
+                FRAME_CONNECT_OK
+            },
+            Frame::EarlyData { accepted } => {
+                out.push(*accepted as u8);
+                FRAME_EARLY_DATA
+            },
+            Frame::Open { bidi } => {
+                out.push(*bidi as u8);
+                FRAME_OPEN
+            },
+            Frame::Opened { stream_id } => {
+                out.extend_from_slice(&stream_id.to_be_bytes());
+                FRAME_OPENED
+            },
+            Frame::NewStream { stream_id } => {
+                out.extend_from_slice(&stream_id.to_be_bytes());
+                FRAME_NEW_STREAM
+            },
+            Frame::Accept { bidi } => {
+                out.push(*bidi as u8);
+                FRAME_ACCEPT
+            },
+            Frame::Data { stream_id, data } => {
+                out.extend_from_slice(&stream_id.to_be_bytes());
+                out.extend_from_slice(data);
+                FRAME_DATA
+            },
+            Frame::Credit { stream_id, bytes } => {
+                out.extend_from_slice(&stream_id.to_be_bytes());
+                out.extend_from_slice(&bytes.to_be_bytes());
+                FRAME_CREDIT
+            },
+            Frame::Fin { stream_id } => {
+                out.extend_from_slice(&stream_id.to_be_bytes());
+                FRAME_FIN
+            },
+            Frame::Reset { stream_id, code } => {
+                out.extend_from_slice(&stream_id.to_be_bytes());
+                out.extend_from_slice(&code.to_be_bytes());
+                FRAME_RESET
+            },
+            Frame::StopSending { stream_id, code } => {
+                out.extend_from_slice(&stream_id.to_be_bytes());
+                out.extend_from_slice(&code.to_be_bytes());
+                FRAME_STOP_SENDING
+            },
+            Frame::DatagramOpen => FRAME_DATAGRAM_OPEN,
+            Frame::DatagramOpened { flow_id } => {
+                out.extend_from_slice(&flow_id.to_be_bytes());
+                FRAME_DATAGRAM_OPENED
+            },
+            Frame::Datagram { data } => {
+                out.extend_from_slice(data);
+                FRAME_DATAGRAM
+            },
+            Frame::Error(err) => {
+                out.extend_from_slice(&err.to_wire());
+                FRAME_ERROR
+            },
+            Frame::StatsRequest => FRAME_STATS_REQUEST,
+            Frame::Stats(stats) => {
+                let rtt = stats.rtt.as_micros() as u64;
+                for v in [rtt, stats.cwnd, stats.sent, stats.recv, stats.lost,
+                          stats.sent_bytes, stats.recv_bytes, stats.delivery_rate] {
+                    out.extend_from_slice(&v.to_be_bytes());
+                }
+                put_string(out, &stats.congestion_control);
+                FRAME_STATS
+            },
+        };
+        let len = (out.len() - start - FRAME_HEADER_LEN) as u32;
+        out[start] = ty;
+        out[start + 1..start + FRAME_HEADER_LEN].copy_from_slice(&len.to_be_bytes());
+    }
+
+
+    /// Encode frame into new buffer.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+
+
+    /// Decode frame of type `ty` from its payload.
+    fn decode(ty: u8, payload: &[u8]) -> Result<Frame> {
+        let mut r = Reader { buf: payload };
+        let frame = match ty {
+            FRAME_HELLO => Frame::Hello { version: r.get_u16()? },
+            FRAME_CONNECT => Frame::Connect {
+                address: r.get_string()?,
+                app_proto: r.get_string()?,
+                server_name: r.get_optional_string()?,
+                identity: r.get_optional_string()?,
+                early_data: r.get_optional_bool()?,
+                congestion_control: r.get_optional_string()?,
+            },
+            FRAME_CONNECT_OK => Frame::ConnectOk {
+                early_data: r.get_optional_bool()?,
+                congestion_control: r.get_optional_string()?,
+            },
+            FRAME_EARLY_DATA => Frame::EarlyData { accepted: r.get_u8()? != 0 },
+            FRAME_OPEN => Frame::Open { bidi: r.get_u8()? != 0 },
+            FRAME_OPENED => Frame::Opened { stream_id: r.get_u64()? },
+            FRAME_NEW_STREAM => Frame::NewStream { stream_id: r.get_u64()? },
+            FRAME_ACCEPT => Frame::Accept { bidi: r.get_u8()? != 0 },
+            FRAME_DATA => Frame::Data {
+                stream_id: r.get_u64()?,
+                data: r.buf.to_vec(),
+            },
+            FRAME_CREDIT => Frame::Credit { stream_id: r.get_u64()?, bytes: r.get_u64()? },
+            FRAME_FIN => Frame::Fin { stream_id: r.get_u64()? },
+            FRAME_RESET => Frame::Reset { stream_id: r.get_u64()?, code: r.get_u64()? },
+            FRAME_STOP_SENDING => Frame::StopSending {
+                stream_id: r.get_u64()?,
+                code: r.get_u64()?,
+            },
+            FRAME_DATAGRAM_OPEN => Frame::DatagramOpen,
+            FRAME_DATAGRAM_OPENED => Frame::DatagramOpened { flow_id: r.get_u64()? },
+            FRAME_DATAGRAM => Frame::Datagram { data: payload.to_vec() },
+            FRAME_ERROR => Frame::Error(Error::from_wire(payload)),
+            FRAME_STATS_REQUEST => Frame::StatsRequest,
+            FRAME_STATS => Frame::Stats(ConnectionStats {
+                rtt: Duration::from_micros(r.get_u64()?),
+                cwnd: r.get_u64()?,
+                sent: r.get_u64()?,
+                recv: r.get_u64()?,
+                lost: r.get_u64()?,
+                sent_bytes: r.get_u64()?,
+                recv_bytes: r.get_u64()?,
+                delivery_rate: r.get_u64()?,
+                congestion_control: r.get_optional_string()?.unwrap_or_default(),
+            }),
+            _ => return Err(Error::Protocol(format!("Unknown frame type {:#04x}", ty))),
+        };
+        Ok(frame)
+    }
+}
+
+
+/// Collects bytes read from Unix socket and splits them into frames.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder { buf: Vec::new() }
+    }
+
+
+    /// Add bytes read from the socket.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+
+    /// Returns next complete frame, or None if more bytes are needed.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>> {
+        if self.buf.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+        let ty = self.buf[0];
+        let len = u32::from_be_bytes(self.buf[1..FRAME_HEADER_LEN].try_into().unwrap()) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(Error::Protocol(format!("Frame too long: {} bytes", len)));
+        }
+        if self.buf.len() < FRAME_HEADER_LEN + len {
+            return Ok(None);
+        }
+        let frame = Frame::decode(ty, &self.buf[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len]);
+        self.buf.drain(..FRAME_HEADER_LEN + len);
+        frame.map(Some)
+    }
+
+
+    /// Returns true if there are buffered bytes of an incomplete frame.
+    pub fn has_partial(&self) -> bool {
+        !self.buf.is_empty()
+    }
+}
+
+
+/// Encode ERROR frame. Unlike `Frame::Error`, does not need ownership of the
+/// error, so the same error can be sent to several clients.
+pub fn error_frame(err: &Error) -> Vec<u8> {
+    let payload = err.to_wire();
+    let mut out = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    out.push(FRAME_ERROR);
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(&payload);
+    out
+}
+
+
+/// Select protocol version for client that offered `version`.
+pub fn negotiate_version(version: u16) -> Result<u16> {
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(Error::Protocol(format!(
+            "Unsupported protocol version {}, oldest supported is {}",
+            version, MIN_PROTOCOL_VERSION
+        )));
+    }
+    Ok(std::cmp::min(version, PROTOCOL_VERSION))
+}
+
+
+/// Append string with u16 length. Panics if the string is longer than
+/// [`MAX_STRING_LEN`], so strings from applications need to be checked before
+/// they are put in frames.
+fn put_string(out: &mut Vec<u8>, s: &str) {
+    assert!(s.len() <= MAX_STRING_LEN, "String of {} bytes does not fit in frame", s.len());
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+
+/// Reads fields from frame payload.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn get_bytes(&mut self, n: usize) -> Result<&[u8]> {
+        if self.buf.len() < n {
+            return Err(Error::Protocol("Truncated frame".to_string()));
+        }
+        let (v, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(v)
+    }
+
+
+    fn get_u8(&mut self) -> Result<u8> {
+        Ok(self.get_bytes(1)?[0])
+    }
+
+
+    fn get_u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.get_bytes(2)?.try_into().unwrap()))
+    }
+
+
+    fn get_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.get_bytes(8)?.try_into().unwrap()))
+    }
+
+
+    fn get_string(&mut self) -> Result<String> {
+        let len = self.get_u16()? as usize;
+        match std::str::from_utf8(self.get_bytes(len)?) {
+            Ok(s) => Ok(s.to_string()),
+            Err(e) => Err(Error::Protocol(format!("Invalid string in frame: {}", e))),
+        }
+    }
+
+
+    /// Read optional string at the end of payload. Empty or missing string
+    /// means None, so that frames from clients that do not send the field are
+    /// accepted.
+    fn get_optional_string(&mut self) -> Result<Option<String>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let s = self.get_string()?;
+        Ok(if s.is_empty() { None } else { Some(s) })
+    }
+
+
+    /// Read optional flag at the end of payload. Missing flag is false.
+    fn get_optional_bool(&mut self) -> Result<bool> {
+        if self.buf.is_empty() {
+            return Ok(false);
+        }
+        Ok(self.get_u8()? != 0)
+    }
+}
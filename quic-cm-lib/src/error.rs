@@ -0,0 +1,172 @@
+use std::{fmt, io};
+
+
+/// Errors reported by QUIC-CM library and manager.
+///
+/// Manager sends errors to client applications as structured ERRO messages, so
+/// that the client can reconstruct the same variant that manager observed.
+#[derive(Debug)]
+pub enum Error {
+    /// Could not connect to the control socket of QUIC-CM manager.
+    ManagerUnreachable(io::Error),
+    /// Destination address could not be resolved.
+    Dns(String),
+    /// QUIC handshake with the server failed.
+    Handshake(String),
+    /// Server certificate could not be verified.
+    TlsVerification(String),
+    /// Server does not support the requested application protocol.
+    AlpnMismatch(String),
+    /// Peer reset the stream with given application error code.
+    StreamReset(u64),
+    /// Peer asked to stop sending on the stream with given application error
+    /// code.
+    StreamStopped(u64),
+    /// Server has not enabled QUIC DATAGRAM frames on the connection.
+    DatagramUnsupported,
+    /// Manager does not allow the user to use requested client identity.
+    PermissionDenied(String),
+    /// QUIC connection was closed with given error code and reason.
+    ConnectionClosed { code: u64, reason: String },
+    /// Unexpected or malformed message between application and manager.
+    Protocol(String),
+    /// I/O error on the Unix socket.
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Error codes used in ERRO messages.
+const CODE_MANAGER_UNREACHABLE: u16 = 1;
+const CODE_DNS: u16 = 2;
+const CODE_HANDSHAKE: u16 = 3;
+const CODE_TLS_VERIFICATION: u16 = 4;
+const CODE_ALPN_MISMATCH: u16 = 5;
+const CODE_STREAM_RESET: u16 = 6;
+const CODE_CONNECTION_CLOSED: u16 = 7;
+const CODE_PROTOCOL: u16 = 8;
+const CODE_IO: u16 = 9;
+const CODE_STREAM_STOPPED: u16 = 10;
+const CODE_DATAGRAM_UNSUPPORTED: u16 = 11;
+const CODE_PERMISSION_DENIED: u16 = 12;
+
+impl Error {
+
+    /// Encode error as ERRO message payload: u16 error code, u64 application
+    /// or transport error code, followed by UTF-8 message.
+    pub fn to_wire(&self) -> Vec<u8> {
+        let (code, value, message) = match self {
+            Error::ManagerUnreachable(e) => (CODE_MANAGER_UNREACHABLE, 0, e.to_string()),
+            Error::Dns(m) => (CODE_DNS, 0, m.clone()),
+            Error::Handshake(m) => (CODE_HANDSHAKE, 0, m.clone()),
+            Error::TlsVerification(m) => (CODE_TLS_VERIFICATION, 0, m.clone()),
+            Error::AlpnMismatch(m) => (CODE_ALPN_MISMATCH, 0, m.clone()),
+            Error::StreamReset(c) => (CODE_STREAM_RESET, *c, String::new()),
+            Error::StreamStopped(c) => (CODE_STREAM_STOPPED, *c, String::new()),
+            Error::DatagramUnsupported => (CODE_DATAGRAM_UNSUPPORTED, 0, String::new()),
+            Error::PermissionDenied(m) => (CODE_PERMISSION_DENIED, 0, m.clone()),
+            Error::ConnectionClosed { code, reason } =>
+                (CODE_CONNECTION_CLOSED, *code, reason.clone()),
+            Error::Protocol(m) => (CODE_PROTOCOL, 0, m.clone()),
+            Error::Io(e) => (CODE_IO, 0, e.to_string()),
+        };
+        let mut buf = Vec::with_capacity(10 + message.len());
+        buf.extend_from_slice(&code.to_be_bytes());
+        buf.extend_from_slice(&value.to_be_bytes());
+        buf.extend_from_slice(message.as_bytes());
+        buf
+    }
+
+
+    /// Decode error from ERRO message payload.
+    pub fn from_wire(buf: &[u8]) -> Error {
+        if buf.len() < 10 {
+            return Error::Protocol(format!("Truncated error message: {} bytes", buf.len()));
+        }
+        let code = u16::from_be_bytes(buf[..2].try_into().unwrap());
+        let value = u64::from_be_bytes(buf[2..10].try_into().unwrap());
+        let message = String::from_utf8_lossy(&buf[10..]).to_string();
+        match code {
+            CODE_MANAGER_UNREACHABLE => Error::ManagerUnreachable(io::Error::other(message)),
+            CODE_DNS => Error::Dns(message),
+            CODE_HANDSHAKE => Error::Handshake(message),
+            CODE_TLS_VERIFICATION => Error::TlsVerification(message),
+            CODE_ALPN_MISMATCH => Error::AlpnMismatch(message),
+            CODE_STREAM_RESET => Error::StreamReset(value),
+            CODE_STREAM_STOPPED => Error::StreamStopped(value),
+            CODE_DATAGRAM_UNSUPPORTED => Error::DatagramUnsupported,
+            CODE_PERMISSION_DENIED => Error::PermissionDenied(message),
+            CODE_CONNECTION_CLOSED => Error::ConnectionClosed { code: value, reason: message },
+            CODE_PROTOCOL => Error::Protocol(message),
+            CODE_IO => Error::Io(io::Error::other(message)),
+            _ => Error::Protocol(format!("Unknown error code {}: {}", code, message)),
+        }
+    }
+
+
+    fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            Error::ManagerUnreachable(_) => io::ErrorKind::NotConnected,
+            Error::StreamReset(_) => io::ErrorKind::ConnectionReset,
+            Error::StreamStopped(_) => io::ErrorKind::BrokenPipe,
+            Error::DatagramUnsupported => io::ErrorKind::Unsupported,
+            Error::PermissionDenied(_) => io::ErrorKind::PermissionDenied,
+            Error::ConnectionClosed { .. } => io::ErrorKind::ConnectionAborted,
+            Error::Protocol(_) => io::ErrorKind::InvalidData,
+            Error::Io(e) => e.kind(),
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ManagerUnreachable(e) => write!(f, "QUIC-CM manager unreachable: {}", e),
+            Error::Dns(m) => write!(f, "Address resolution failed: {}", m),
+            Error::Handshake(m) => write!(f, "Handshake failed: {}", m),
+            Error::TlsVerification(m) => write!(f, "TLS verification failed: {}", m),
+            Error::AlpnMismatch(m) => write!(f, "Application protocol mismatch: {}", m),
+            Error::StreamReset(c) => write!(f, "Stream reset by peer with code {}", c),
+            Error::StreamStopped(c) => write!(f, "Peer stopped reading stream with code {}", c),
+            Error::DatagramUnsupported => write!(f, "Peer does not support QUIC datagrams"),
+            Error::PermissionDenied(m) => write!(f, "Permission denied: {}", m),
+            Error::ConnectionClosed { code, reason } =>
+                write!(f, "Connection closed with code {}: {}", code, reason),
+            Error::Protocol(m) => write!(f, "QUIC-CM protocol violation: {}", m),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ManagerUnreachable(e) | Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+
+impl From<io::Error> for Error {
+    /// Unwraps the QUIC-CM error, if the I/O error carries one.
+    fn from(e: io::Error) -> Error {
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(e)
+    }
+}
+
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.io_kind(), e),
+        }
+    }
+}
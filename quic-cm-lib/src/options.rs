@@ -0,0 +1,71 @@
+//! Options for connecting to a destination.
+
+/// Optional settings of a connection request, given to `connect_with`
+/// functions. Default options are used by `connect`.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    pub(crate) server_name: Option<String>,
+    pub(crate) identity: Option<String>,
+    pub(crate) early_data: bool,
+    pub(crate) congestion_control: Option<String>,
+}
+
+impl ConnectOptions {
+    pub fn new() -> ConnectOptions {
+        ConnectOptions::default()
+    }
+
+
+    /// Server name to send in TLS SNI and to verify the server certificate
+    /// against. By default the host in the address is used, if it is a DNS
+    /// name. Setting the name allows connecting by IP address to a server that
+    /// has a certificate for a name. Clients share a connection only if they
+    /// use the same server name.
+    pub fn server_name(mut self, name: &str) -> ConnectOptions {
+        self.server_name = Some(name.to_string());
+        self
+    }
+
+
+    /// Client certificate to authenticate with, by its name in manager
+    /// configuration. Manager decides which users may use which identities, and
+    /// fails the connection with [`Error::PermissionDenied`] if the user may not
+    /// use it. Without this, manager may use an identity configured for the
+    /// destination. Clients share a connection only if they use the same
+    /// identity.
+    ///
+    /// [`Error::PermissionDenied`]: crate::Error::PermissionDenied
+    pub fn identity(mut self, name: &str) -> ConnectOptions {
+        self.identity = Some(name.to_string());
+        self
+    }
+
+
+    /// Allow data to be sent in 0-RTT, when manager resumes an earlier TLS
+    /// session with the destination. Connecting then completes before the
+    /// handshake, and the first writes are sent as early data. Early data can
+    /// be replayed by an attacker, so it should only be used when the
+    /// application protocol tolerates that. Whether the server accepted the
+    /// early data is available from `early_data_accepted` of the session.
+    ///
+    /// Manager does not use early data to destinations that have pinned
+    /// certificates, because the data would be sent before the certificate is
+    /// checked against the pins.
+    pub fn early_data(mut self, enable: bool) -> ConnectOptions {
+        self.early_data = enable;
+        self
+    }
+
+
+    /// Congestion control algorithm for the connection: "reno", "cubic",
+    /// "bbr" or "bbr2". Congestion state is shared by all clients of a
+    /// connection, so this only takes effect when manager opens a new
+    /// connection for the client; otherwise the client joins an existing one
+    /// with the algorithm it already has. Algorithm in use is reported by
+    /// `congestion_control` of the session and by `stats`. By default manager
+    /// configuration decides.
+    pub fn congestion_control(mut self, name: &str) -> ConnectOptions {
+        self.congestion_control = Some(name.to_string());
+        self
+    }
+}
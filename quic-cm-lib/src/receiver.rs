@@ -0,0 +1,454 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+};
+
+use crate::{
+    ConnectOptions,
+    Error,
+    Result,
+    common::{
+        MAX_DATA_LEN,
+        RECV_WINDOW,
+        is_bidi,
+        is_server_initiated,
+        codec::{
+            ConnectionStats, Frame, FrameDecoder, MAX_STRING_LEN, PROTOCOL_VERSION,
+            negotiate_version,
+        },
+    },
+};
+
+
+/// Number of received datagrams that are kept until the application reads
+/// them. Older datagrams are dropped when more arrive.
+const MAX_QUEUED_DATAGRAMS: usize = 1024;
+
+
+/// State of one stream in the session.
+#[derive(Default)]
+struct StreamState {
+    /// Payload from DATA frames that is not yet read by the application.
+    /// Limited by the receive window.
+    data: Vec<u8>,
+    /// Number of bytes that manager may still deliver in DATA frames.
+    window: u64,
+    /// Number of bytes read by the application, but not yet granted back to
+    /// manager as credit.
+    consumed: u64,
+    /// Peer has finished sending.
+    eof: bool,
+    /// Application error code, if peer has reset the stream.
+    reset: Option<u64>,
+    /// Application error code, if peer has asked to stop sending.
+    stopped: Option<u64>,
+    /// Number of bytes that client may still send in DATA frames.
+    credit: u64,
+    /// Application does not read the stream anymore.
+    read_closed: bool,
+    /// Application has finished or reset sending on the stream.
+    write_closed: bool,
+}
+
+
+/// Parses frames that manager sends to the client application, and keeps
+/// track of received data, receive window and write credit of each stream.
+/// Shared by the async and blocking clients, which only differ in how they do
+/// I/O on the Unix socket.
+///
+/// Streams that have been closed in both directions by the application are
+/// forgotten. Such streams appear to be at end of file, and closed for writing.
+pub(crate) struct Receiver {
+    decoder: FrameDecoder,
+    /// Protocol version selected by manager.
+    version: Option<u16>,
+    connected: bool,
+    /// Session was connected in 0-RTT.
+    early_data: bool,
+    /// Whether the server accepted early data, known when the handshake has
+    /// completed.
+    early_data_accepted: Option<bool>,
+    /// Congestion control algorithm of the connection, if manager told it.
+    congestion_control: Option<String>,
+    streams: HashMap<u64, StreamState>,
+    /// Streams opened on request of this client, but not yet taken into use.
+    opened: VecDeque<u64>,
+    /// Streams opened by the server, but not yet accepted by the application.
+    accepted: VecDeque<u64>,
+    stats: VecDeque<ConnectionStats>,
+    /// Datagram flow ID assigned by manager.
+    flow: Option<u64>,
+    /// Received datagrams, not yet read by the application.
+    datagrams: VecDeque<Vec<u8>>,
+    /// Manager has closed the socket.
+    closed: bool,
+}
+
+impl Receiver {
+    pub fn new() -> Receiver {
+        Receiver {
+            decoder: FrameDecoder::new(),
+            version: None,
+            connected: false,
+            early_data: false,
+            early_data_accepted: None,
+            congestion_control: None,
+            streams: HashMap::new(),
+            opened: VecDeque::new(),
+            accepted: VecDeque::new(),
+            stats: VecDeque::new(),
+            flow: None,
+            datagrams: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+
+    /// Add bytes read from Unix socket and process complete frames from them.
+    /// Partial frame is left in the decoder until rest of it arrives.
+    /// Empty `bytes` means that manager has closed the socket. Reading reaches
+    /// end of file after either that, or FIN from peer.
+    pub fn feed(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if bytes.is_empty() {
+            self.closed = true;
+            return Ok(());
+        }
+        self.decoder.feed(bytes);
+        while let Some(frame) = self.decoder.next_frame()? {
+            match frame {
+                Frame::Hello { version } => {
+                    if negotiate_version(version)? != version {
+                        return Err(Error::Protocol(format!(
+                            "Manager selected unsupported version {}", version
+                        )).into());
+                    }
+                    self.version = Some(version);
+                },
+                Frame::ConnectOk { early_data, congestion_control } => {
+                    self.connected = true;
+                    self.early_data = early_data;
+                    self.congestion_control = congestion_control;
+                },
+                Frame::EarlyData { accepted } => self.early_data_accepted = Some(accepted),
+                Frame::Opened { stream_id } => {
+                    self.add_stream(stream_id);
+                    self.opened.push_back(stream_id);
+                },
+                Frame::NewStream { stream_id } => {
+                    self.add_stream(stream_id);
+                    self.accepted.push_back(stream_id);
+                },
+                Frame::Data { stream_id, data } => {
+                    debug!("Read DATA frame with {} bytes on stream {}", data.len(), stream_id);
+                    if let Some(stream) = self.stream_mut(stream_id) {
+                        if data.len() as u64 > stream.window {
+                            return Err(Error::Protocol(format!(
+                                "Manager sent {} bytes on stream {} with window for {}",
+                                data.len(), stream_id, stream.window
+                            )).into());
+                        }
+                        stream.window -= data.len() as u64;
+                        if !stream.read_closed {
+                            stream.data.extend_from_slice(&data);
+                        }
+                    }
+                },
+                Frame::Credit { stream_id, bytes } => {
+                    if let Some(stream) = self.stream_mut(stream_id) {
+                        stream.credit += bytes;
+                    }
+                },
+                Frame::Fin { stream_id } => {
+                    debug!("Peer finished sending on stream {}", stream_id);
+                    if let Some(stream) = self.stream_mut(stream_id) {
+                        stream.eof = true;
+                    }
+                },
+                Frame::Reset { stream_id, code } => {
+                    debug!("Peer reset stream {} with code {}", stream_id, code);
+                    if let Some(stream) = self.stream_mut(stream_id) {
+                        stream.reset = Some(code);
+                        stream.data.clear();
+                    }
+                },
+                Frame::StopSending { stream_id, code } => {
+                    debug!("Peer stopped reading stream {} with code {}", stream_id, code);
+                    if let Some(stream) = self.stream_mut(stream_id) {
+                        stream.stopped = Some(code);
+                    }
+                },
+                Frame::Stats(stats) => self.stats.push_back(stats),
+                Frame::DatagramOpened { flow_id } => self.flow = Some(flow_id),
+                Frame::Datagram { data } => {
+                    if self.datagrams.len() == MAX_QUEUED_DATAGRAMS {
+                        debug!("Datagram queue is full, dropping oldest");
+                        self.datagrams.pop_front();
+                    }
+                    self.datagrams.push_back(data);
+                },
+                Frame::Error(err) => return Err(err.into()),
+                frame => {
+                    return Err(Error::Protocol(format!("Unexpected frame: {:?}", frame)).into());
+                },
+            }
+        }
+        Ok(())
+    }
+
+
+    /// Start tracking a new stream. Direction that a unidirectional stream
+    /// does not have is closed from the beginning. Manager may deliver the
+    /// initial receive window without waiting for credit.
+    fn add_stream(&mut self, stream_id: u64) {
+        let uni = !is_bidi(stream_id);
+        let server = is_server_initiated(stream_id);
+        self.streams.insert(stream_id, StreamState {
+            window: if uni && !server { 0 } else { RECV_WINDOW },
+            read_closed: uni && !server,
+            write_closed: uni && server,
+            ..Default::default()
+        });
+    }
+
+
+    fn stream_mut(&mut self, stream_id: u64) -> Option<&mut StreamState> {
+        self.streams.get_mut(&stream_id)
+    }
+
+
+    /// Forget stream if application has closed both directions of it.
+    fn prune(&mut self, stream_id: u64) {
+        if self.streams.get(&stream_id).is_some_and(|s| s.read_closed && s.write_closed) {
+            self.streams.remove(&stream_id);
+        }
+    }
+
+
+    /// Copy received data of a stream to `buf`. Returns number of bytes copied.
+    pub fn read_data(&mut self, stream_id: u64, buf: &mut [u8]) -> usize {
+        let stream = match self.stream_mut(stream_id) {
+            Some(stream) => stream,
+            None => return 0,
+        };
+        let n = std::cmp::min(buf.len(), stream.data.len());
+        buf[..n].copy_from_slice(&stream.data[..n]);
+        stream.data.drain(..n);
+        stream.consumed += n as u64;
+        n
+    }
+
+
+    /// Returns credit to grant to manager for data that the application has
+    /// read from the stream, if there is enough of it to be worth a CREDIT
+    /// frame. Nothing is granted once the peer has finished sending.
+    pub fn take_read_credit(&mut self, stream_id: u64) -> Option<u64> {
+        let stream = self.stream_mut(stream_id)?;
+        if stream.consumed < RECV_WINDOW / 4 || stream.eof || stream.reset.is_some() {
+            return None;
+        }
+        let bytes = std::mem::take(&mut stream.consumed);
+        stream.window += bytes;
+        Some(bytes)
+    }
+
+
+    pub fn has_data(&self, stream_id: u64) -> bool {
+        self.streams.get(&stream_id).is_some_and(|s| !s.data.is_empty())
+    }
+
+
+    pub fn is_eof(&self, stream_id: u64) -> bool {
+        self.closed || self.streams.get(&stream_id).is_none_or(|s| s.eof || s.read_closed)
+    }
+
+
+    /// Returns error if peer has reset the stream.
+    pub fn check_read(&self, stream_id: u64) -> io::Result<()> {
+        match self.streams.get(&stream_id).and_then(|s| s.reset) {
+            Some(code) => Err(Error::StreamReset(code).into()),
+            None => Ok(()),
+        }
+    }
+
+
+    /// Returns error if sending on the stream has been closed, or if peer has
+    /// asked to stop sending.
+    pub fn check_write(&self, stream_id: u64) -> io::Result<()> {
+        let stream = match self.streams.get(&stream_id) {
+            Some(stream) if !stream.write_closed => stream,
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Write after shutdown"));
+            },
+        };
+        match stream.stopped {
+            Some(code) => Err(Error::StreamStopped(code).into()),
+            None => Ok(()),
+        }
+    }
+
+
+    /// Application stops reading: buffered data is discarded, and further
+    /// reads return end of file. Returns false if reading was already closed.
+    pub fn close_read(&mut self, stream_id: u64) -> bool {
+        let stream = match self.stream_mut(stream_id) {
+            Some(stream) if !stream.read_closed => stream,
+            _ => return false,
+        };
+        stream.data.clear();
+        stream.read_closed = true;
+        self.prune(stream_id);
+        true
+    }
+
+
+    /// Application has finished or reset sending. Returns false if sending was
+    /// already closed.
+    pub fn close_write(&mut self, stream_id: u64) -> bool {
+        match self.stream_mut(stream_id) {
+            Some(stream) if !stream.write_closed => stream.write_closed = true,
+            _ => return false,
+        };
+        self.prune(stream_id);
+        true
+    }
+
+
+    /// Returns true if peer has finished or reset the stream, so that there is
+    /// no need to ask it to stop sending.
+    #[cfg(feature = "tokio")]
+    pub fn peer_finished(&self, stream_id: u64) -> bool {
+        self.streams.get(&stream_id).is_none_or(|s| s.eof || s.reset.is_some())
+    }
+
+
+    /// Returns true when manager has accepted protocol version and connected.
+    pub fn is_connected(&self) -> bool {
+        self.version.is_some() && self.connected
+    }
+
+
+    /// Returns number of bytes that can be sent without waiting for more
+    /// credit, limited to what fits in one DATA frame.
+    pub fn credit(&self, stream_id: u64) -> usize {
+        let credit = self.streams.get(&stream_id).map_or(0, |s| s.credit);
+        std::cmp::min(credit, MAX_DATA_LEN as u64) as usize
+    }
+
+
+    /// Use credit for DATA frame with `n` bytes.
+    pub fn use_credit(&mut self, stream_id: u64, n: usize) {
+        if let Some(stream) = self.stream_mut(stream_id) {
+            stream.credit -= n as u64;
+        }
+    }
+
+
+    /// Take stream that manager has opened on request of this client.
+    pub fn take_opened(&mut self, bidi: bool) -> Option<u64> {
+        take_stream(&mut self.opened, bidi)
+    }
+
+
+    /// Take stream that the server has opened.
+    #[cfg(feature = "tokio")]
+    pub fn take_accepted(&mut self, bidi: bool) -> Option<u64> {
+        take_stream(&mut self.accepted, bidi)
+    }
+
+
+    /// Returns None while the handshake of a session connected in 0-RTT is in
+    /// progress. Then returns whether the server accepted early data, or None
+    /// inside if the session was not connected in 0-RTT.
+    pub fn early_data_status(&self) -> Option<Option<bool>> {
+        if !self.early_data {
+            return Some(None);
+        }
+        self.early_data_accepted.map(Some)
+    }
+
+
+    /// Congestion control algorithm of the connection, as reported when
+    /// connected.
+    pub fn congestion_control(&self) -> Option<&str> {
+        self.congestion_control.as_deref()
+    }
+
+
+    /// Consume connection statistics, if they have been received.
+    pub fn take_stats(&mut self) -> Option<ConnectionStats> {
+        self.stats.pop_front()
+    }
+
+
+    /// Returns datagram flow ID, once manager has assigned it.
+    #[cfg(feature = "tokio")]
+    pub fn flow(&self) -> Option<u64> {
+        self.flow
+    }
+
+
+    /// Consume the oldest received datagram.
+    #[cfg(feature = "tokio")]
+    pub fn take_datagram(&mut self) -> Option<Vec<u8>> {
+        self.datagrams.pop_front()
+    }
+
+
+    /// Error to return when a response is expected, but socket is closed.
+    pub fn closed_error() -> io::Error {
+        io::Error::new(io::ErrorKind::UnexpectedEof, "Control socket closed prematurely")
+    }
+}
+
+
+/// Remove first bidirectional or unidirectional stream from `queue`.
+fn take_stream(queue: &mut VecDeque<u64>, bidi: bool) -> Option<u64> {
+    let pos = queue.iter().position(|id| is_bidi(*id) == bidi)?;
+    queue.remove(pos)
+}
+
+
+/// Build frames that start the session: HELLO followed by CONNECT. Fails if
+/// the address or any of the names is too long for the frame.
+pub(crate) fn connect_message(
+    address: &str,
+    app_proto: &str,
+    options: &ConnectOptions,
+) -> Result<Vec<u8>> {
+    let names = [
+        Some(address),
+        Some(app_proto),
+        options.server_name.as_deref(),
+        options.identity.as_deref(),
+        options.congestion_control.as_deref(),
+    ];
+    if names.iter().flatten().any(|name| name.len() > MAX_STRING_LEN) {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::InvalidInput, "Too long name in connection request"
+        )));
+    }
+    let mut msg = Frame::Hello { version: PROTOCOL_VERSION }.to_bytes();
+    Frame::Connect {
+        address: address.to_string(),
+        app_proto: app_proto.to_string(),
+        server_name: options.server_name.clone(),
+        identity: options.identity.clone(),
+        early_data: options.early_data,
+        congestion_control: options.congestion_control.clone(),
+    }.encode(&mut msg);
+    Ok(msg)
+}
+
+
+/// Build DATA frame for stream from `buf`, with at most `limit` bytes. Returns
+/// the frame and number of application bytes it carries.
+pub(crate) fn data_message(stream_id: u64, buf: &[u8], limit: usize) -> (Vec<u8>, usize) {
+    let n = std::cmp::min(buf.len(), limit);
+    (Frame::Data { stream_id, data: buf[..n].to_vec() }.to_bytes(), n)
+}
+
+
+/// Build FIN frame.
+pub(crate) fn fin_message(stream_id: u64) -> Vec<u8> {
+    Frame::Fin { stream_id }.to_bytes()
+}
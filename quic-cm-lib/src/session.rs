@@ -0,0 +1,419 @@
+use std::{
+    future::poll_fn,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Wake, Waker, ready},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UnixStream;
+
+use crate::{
+    ConnectOptions,
+    Error,
+    QuicClient,
+    Result,
+    common::{MAX_DATA_LEN, control_socket_path},
+    common::codec::{ConnectionStats, Frame},
+    receiver::{Receiver, connect_message},
+    split::{OwnedReadHalf, OwnedWriteHalf, halves},
+};
+
+
+/// Session of a client application with QUIC-CM manager, over one Unix socket.
+/// The session is attached to a QUIC connection, and can open any number of
+/// streams on it, or accept streams that the server opens.
+///
+/// Bidirectional streams are represented by [`QuicClient`], and
+/// unidirectional streams by the write or read half alone. The session can be
+/// cloned to open streams from several tasks. The Unix socket is closed, and
+/// streams that are still open are reset, when the session and all of its
+/// streams have been dropped.
+#[derive(Clone)]
+pub struct QuicSession {
+    shared: Arc<Shared>,
+}
+
+impl QuicSession {
+
+    /// Initiate QUIC connection to given address.
+    ///
+    /// Address string is of form `<address>:<port>`. Address can be IP address or
+    /// DNS name. Port can be omitted, in which case default port 7878 is used.
+    /// `app_proto` specifies the application protocol given in QUIC configuration.
+    /// Server must have the same protocol identifier configured.
+    pub async fn connect(address: &str, app_proto: &str) -> Result<QuicSession> {
+        Self::connect_with(address, app_proto, &ConnectOptions::default()).await
+    }
+
+
+    /// Initiate QUIC connection to given address, with `options`. Address and
+    /// `app_proto` are as in [`QuicSession::connect`].
+    pub async fn connect_with(
+        address: &str,
+        app_proto: &str,
+        options: &ConnectOptions,
+    ) -> Result<QuicSession> {
+        let socket = match UnixStream::connect(control_socket_path()).await {
+            Ok(s) => s,
+            Err(e) => return Err(Error::ManagerUnreachable(e)),
+        };
+        let msg = connect_message(address, app_proto, options)?;
+        let shared = Shared::new(socket);
+        shared.queue(&msg);
+        debug!("fifo connect, sending HELLO and CONNECT");
+
+        match poll_fn(|cx| shared.poll_connected(cx)).await {
+            Ok(()) => Ok(QuicSession { shared }),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+
+    /// Open new bidirectional stream.
+    pub async fn open_bidi(&self) -> Result<QuicClient> {
+        let stream_id = self.open(true).await?;
+        let (read, write) = halves(self.shared.clone(), stream_id);
+        Ok(QuicClient::from_halves(read, write))
+    }
+
+
+    /// Open new unidirectional stream for sending.
+    pub async fn open_uni(&self) -> Result<OwnedWriteHalf> {
+        let stream_id = self.open(false).await?;
+        Ok(halves(self.shared.clone(), stream_id).1)
+    }
+
+
+    /// Wait for the server to open a bidirectional stream.
+    ///
+    /// The first accept registers the session for receiving bidirectional
+    /// streams that the server opens. If several sessions of the connection
+    /// accept streams of the kind, manager gives each new stream to one of
+    /// them.
+    pub async fn accept_bidi(&self) -> Result<QuicClient> {
+        self.shared.start_accepting(true).await?;
+        let stream_id = poll_fn(|cx| self.shared.poll_accepted(cx, true)).await?;
+        let (read, write) = halves(self.shared.clone(), stream_id);
+        Ok(QuicClient::from_halves(read, write))
+    }
+
+
+    /// Wait for the server to open a unidirectional stream. Bidirectional and
+    /// unidirectional streams are accepted separately, so a session that only
+    /// calls this is not given bidirectional streams.
+    pub async fn accept_uni(&self) -> Result<OwnedReadHalf> {
+        self.shared.start_accepting(false).await?;
+        let stream_id = poll_fn(|cx| self.shared.poll_accepted(cx, false)).await?;
+        Ok(halves(self.shared.clone(), stream_id).0)
+    }
+
+
+    /// Returns None if the session was not connected in 0-RTT. Otherwise waits
+    /// until the handshake completes, and returns true if the server accepted
+    /// the early data, or false if it was retransmitted after the handshake,
+    /// or its acceptance could not be confirmed.
+    ///
+    /// quiche does not tell early data status, so manager reports early data
+    /// as accepted when the session was resumed, and no stream data is
+    /// retransmitted shortly after the handshake. The answer can therefore
+    /// take about two round-trip times after the handshake. Any stream data
+    /// retransmitted due to packet loss counts as rejection, so accepted
+    /// early data may be reported as not accepted, but not the other way.
+    pub async fn early_data_accepted(&self) -> Result<Option<bool>> {
+        Ok(poll_fn(|cx| self.shared.poll_response(cx, |r| r.early_data_status())).await?)
+    }
+
+
+    /// Congestion control algorithm of the QUIC connection. It differs from
+    /// the one asked with [`ConnectOptions::congestion_control`] when the
+    /// session joined an existing connection. None if manager did not tell.
+    pub fn congestion_control(&self) -> Option<String> {
+        self.shared.lock().receiver.congestion_control().map(str::to_string)
+    }
+
+
+    /// Get statistics of the QUIC connection used by this session.
+    pub async fn stats(&self) -> Result<ConnectionStats> {
+        self.shared.stats().await
+    }
+
+
+    /// Returns the flow ID of this session's datagrams. Datagrams on the QUIC
+    /// connection start with the flow ID as variable-length integer, and the
+    /// server uses it to send datagrams to this session. The first call asks
+    /// manager to assign a flow, and fails with [`Error::DatagramUnsupported`]
+    /// if the server has not enabled datagrams.
+    pub async fn datagram_flow(&self) -> Result<u64> {
+        let requested = std::mem::replace(&mut self.shared.lock().flow_requested, true);
+        if !requested {
+            self.shared.send_frame(Frame::DatagramOpen).await?;
+        }
+        match poll_fn(|cx| self.shared.poll_flow(cx)).await {
+            Ok(flow_id) => Ok(flow_id),
+            Err(e) => {
+                // Ask again on next call.
+                self.shared.lock().flow_requested = false;
+                Err(Error::from(e))
+            },
+        }
+    }
+
+
+    /// Send unreliable datagram to the server. The datagram may be lost, and
+    /// is dropped without error if the connection cannot send it.
+    pub async fn send_datagram(&self, data: &[u8]) -> Result<()> {
+        if data.len() > MAX_DATA_LEN {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput, "Datagram is too large"
+            )));
+        }
+        self.datagram_flow().await?;
+        self.shared.send_frame(Frame::Datagram { data: data.to_vec() }).await
+    }
+
+
+    /// Wait for datagram from the server.
+    pub async fn recv_datagram(&self) -> Result<Vec<u8>> {
+        self.datagram_flow().await?;
+        Ok(poll_fn(|cx| self.shared.poll_response(cx, |r| r.take_datagram())).await?)
+    }
+
+
+    async fn open(&self, bidi: bool) -> Result<u64> {
+        self.shared.send_frame(Frame::Open { bidi }).await?;
+        Ok(poll_fn(|cx| self.shared.poll_opened(cx, bidi)).await?)
+    }
+}
+
+
+/// Wakers of all tasks that wait for the socket. Socket remembers only the
+/// latest waker it was polled with, so it is given this one, which wakes every
+/// task that uses the session. Each of them then checks whether it can make
+/// progress.
+#[derive(Default)]
+struct WakerSet {
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl Wake for WakerSet {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        for waker in self.wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+
+/// State of the Unix socket, shared by the session and its streams.
+pub(crate) struct Inner {
+    socket: UnixStream,
+    pub(crate) receiver: Receiver,
+    /// Frames that are not yet written to the socket.
+    outbuf: Vec<u8>,
+    outpos: usize,
+    /// ACCEPT has been sent to manager for bidirectional streams.
+    accepting_bidi: bool,
+    /// ACCEPT has been sent to manager for unidirectional streams.
+    accepting_uni: bool,
+    /// DATAGRAM_OPEN has been sent to manager.
+    flow_requested: bool,
+}
+
+impl Inner {
+    /// Queue frame to be written after earlier frames.
+    pub(crate) fn queue_frame(&mut self, frame: &Frame) {
+        frame.encode(&mut self.outbuf);
+    }
+
+
+    pub(crate) fn queue(&mut self, bytes: &[u8]) {
+        self.outbuf.extend_from_slice(bytes);
+    }
+
+
+    /// Write queued frames as far as the socket accepts without waiting. Used
+    /// when a stream is dropped, and there is no task to wait on.
+    pub(crate) fn try_send(&mut self) {
+        while self.outpos < self.outbuf.len() {
+            match self.socket.try_write(&self.outbuf[self.outpos..]) {
+                Ok(n) if n > 0 => self.outpos += n,
+                _ => return,
+            }
+        }
+        self.outbuf.clear();
+        self.outpos = 0;
+    }
+}
+
+
+pub(crate) struct Shared {
+    inner: Mutex<Inner>,
+    wakers: Arc<WakerSet>,
+}
+
+impl Shared {
+    fn new(socket: UnixStream) -> Arc<Shared> {
+        Arc::new(Shared {
+            inner: Mutex::new(Inner {
+                socket,
+                receiver: Receiver::new(),
+                outbuf: Vec::new(),
+                outpos: 0,
+                accepting_bidi: false,
+                accepting_uni: false,
+                flow_requested: false,
+            }),
+            wakers: Arc::new(WakerSet::default()),
+        })
+    }
+
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+
+
+    fn queue(&self, bytes: &[u8]) {
+        self.lock().queue(bytes);
+    }
+
+
+    /// Remember the waker of the calling task, and return the waker to
+    /// register with the socket.
+    fn waker(&self, cx: &Context<'_>) -> Waker {
+        let mut wakers = self.wakers.wakers.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Waker::from(self.wakers.clone())
+    }
+
+
+    /// Read more bytes from Unix socket and parse them. Returns number of bytes
+    /// read, 0 means that manager has closed the socket.
+    pub(crate) fn poll_fill(&self, inner: &mut Inner, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let waker = self.waker(cx);
+        let mut cx = Context::from_waker(&waker);
+        let mut buf = [0; 16384];
+        let mut readbuf = ReadBuf::new(&mut buf);
+        ready!(Pin::new(&mut inner.socket).poll_read(&mut cx, &mut readbuf))?;
+        inner.receiver.feed(readbuf.filled())?;
+        // Frames may be for other tasks of the session, such as credit for a
+        // write half read by the read half. The socket does not wake them,
+        // because the bytes are already consumed.
+        self.wakers.wake_by_ref();
+        Poll::Ready(Ok(readbuf.filled().len()))
+    }
+
+
+    /// Write queued frames to Unix socket.
+    pub(crate) fn poll_send(&self, inner: &mut Inner, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let waker = self.waker(cx);
+        let mut cx = Context::from_waker(&waker);
+        while inner.outpos < inner.outbuf.len() {
+            let n = ready!(
+                Pin::new(&mut inner.socket).poll_write(&mut cx, &inner.outbuf[inner.outpos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            inner.outpos += n;
+        }
+        if !inner.outbuf.is_empty() {
+            debug!("Wrote to Unix socket {} bytes", inner.outbuf.len());
+            inner.outbuf.clear();
+            inner.outpos = 0;
+        }
+        ready!(Pin::new(&mut inner.socket).poll_flush(&mut cx))?;
+        Poll::Ready(Ok(()))
+    }
+
+
+    /// Queue frame after earlier frames, and write them to the socket.
+    pub(crate) async fn send_frame(&self, frame: Frame) -> Result<()> {
+        self.lock().queue_frame(&frame);
+        Ok(poll_fn(|cx| self.poll_send(&mut self.lock(), cx)).await?)
+    }
+
+
+    /// Get statistics of the QUIC connection.
+    pub(crate) async fn stats(&self) -> Result<ConnectionStats> {
+        self.send_frame(Frame::StatsRequest).await?;
+        Ok(poll_fn(|cx| self.poll_response(cx, |r| r.take_stats())).await?)
+    }
+
+
+    /// Tell manager that the session accepts bidirectional or unidirectional
+    /// streams opened by the server, unless already done.
+    async fn start_accepting(&self, bidi: bool) -> Result<()> {
+        {
+            let mut inner = self.lock();
+            let accepting = if bidi {
+                &mut inner.accepting_bidi
+            } else {
+                &mut inner.accepting_uni
+            };
+            if *accepting {
+                return Ok(());
+            }
+            *accepting = true;
+        }
+        self.send_frame(Frame::Accept { bidi }).await
+    }
+
+
+    /// Read from socket until `take` returns response from manager.
+    fn poll_response<T>(
+        &self,
+        cx: &mut Context<'_>,
+        mut take: impl FnMut(&mut Receiver) -> Option<T>,
+    ) -> Poll<io::Result<T>> {
+        let mut inner = self.lock();
+        loop {
+            if let Some(v) = take(&mut inner.receiver) {
+                return Poll::Ready(Ok(v));
+            }
+            if ready!(self.poll_fill(&mut inner, cx))? == 0 {
+                return Poll::Ready(Err(Receiver::closed_error()));
+            }
+        }
+    }
+
+
+    /// Wait until manager reports that QUIC connection is ready.
+    fn poll_connected(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send(&mut self.lock(), cx))?;
+        self.poll_response(cx, |r| r.is_connected().then_some(()))
+    }
+
+
+    /// Wait until manager has opened stream requested earlier.
+    fn poll_opened(&self, cx: &mut Context<'_>, bidi: bool) -> Poll<io::Result<u64>> {
+        self.poll_response(cx, |r| r.take_opened(bidi))
+    }
+
+
+    /// Wait until manager has assigned datagram flow.
+    fn poll_flow(&self, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        // DATAGRAM_OPEN may still be waiting in the queue, if another task
+        // sent it.
+        ready!(self.poll_send(&mut self.lock(), cx))?;
+        self.poll_response(cx, |r| r.flow())
+    }
+
+
+    /// Wait until the server opens a stream.
+    fn poll_accepted(&self, cx: &mut Context<'_>, bidi: bool) -> Poll<io::Result<u64>> {
+        // ACCEPT may still be waiting in the queue, if another task sent it.
+        ready!(self.poll_send(&mut self.lock(), cx))?;
+        self.poll_response(cx, |r| r.take_accepted(bidi))
+    }
+}
+
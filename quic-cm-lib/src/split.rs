@@ -0,0 +1,246 @@
+//! Read and write halves of [`QuicClient`](crate::QuicClient).
+//!
+//! [`QuicClient::into_split`](crate::QuicClient::into_split) returns halves
+//! that can be moved to different tasks, so that reading and writing proceed
+//! concurrently. The halves share the Unix socket of the session with other
+//! streams, and whichever task reads from the socket parses the frames for all
+//! of them. Unidirectional streams are represented by one half alone.
+
+use std::{
+    future::poll_fn,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    Result,
+    common::CLIENT_GONE_ERROR_CODE,
+    common::codec::{ConnectionStats, Frame},
+    receiver::{Receiver, data_message, fin_message},
+    session::{Inner, Shared},
+};
+
+
+/// Owned read half of [`QuicClient`](crate::QuicClient), created by
+/// [`into_split`](crate::QuicClient::into_split), or a unidirectional stream
+/// opened by the server.
+///
+/// Dropping the read half before the peer has finished sending asks the peer to
+/// stop sending.
+pub struct OwnedReadHalf {
+    shared: Arc<Shared>,
+    stream_id: u64,
+}
+
+
+/// Owned write half of [`QuicClient`](crate::QuicClient), created by
+/// [`into_split`](crate::QuicClient::into_split), or a unidirectional stream
+/// opened by this client.
+///
+/// Dropping the write half without shutting it down first resets the stream.
+pub struct OwnedWriteHalf {
+    shared: Arc<Shared>,
+    stream_id: u64,
+    /// Number of application bytes in the DATA frame that this half has queued,
+    /// but that is not yet written to the socket.
+    outlen: Option<usize>,
+}
+
+
+/// Create read and write halves of stream `stream_id`.
+pub(crate) fn halves(shared: Arc<Shared>, stream_id: u64) -> (OwnedReadHalf, OwnedWriteHalf) {
+    (
+        OwnedReadHalf { shared: shared.clone(), stream_id },
+        OwnedWriteHalf { shared, stream_id, outlen: None },
+    )
+}
+
+
+impl OwnedReadHalf {
+
+    /// Returns the QUIC stream ID.
+    pub fn stream_id(&self) -> u64 {
+        self.stream_id
+    }
+
+
+    /// Ask the peer to stop sending on the QUIC stream, with application error
+    /// `code`. Data that is not yet read is discarded, and further reads return
+    /// end of file.
+    pub async fn stop_reading(&mut self, code: u64) -> Result<()> {
+        if !self.shared.lock().receiver.close_read(self.stream_id) {
+            return Ok(());
+        }
+        self.shared.send_frame(Frame::StopSending { stream_id: self.stream_id, code }).await
+    }
+}
+
+
+impl OwnedReadHalf {
+    /// Write queued frames, including credit for data that has been read. If
+    /// the socket buffer is full, the rest is written when the task is woken
+    /// up, or by another task of the session.
+    fn poll_send_credit(&self, inner: &mut Inner, cx: &mut Context<'_>) -> io::Result<()> {
+        match self.shared.poll_send(inner, cx) {
+            Poll::Ready(Err(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+
+impl OwnedWriteHalf {
+
+    /// Returns the QUIC stream ID.
+    pub fn stream_id(&self) -> u64 {
+        self.stream_id
+    }
+
+
+    /// Send FIN on the QUIC stream, after data written earlier. Data can still
+    /// be read from the read half after this.
+    pub async fn shutdown_write(&mut self) -> Result<()> {
+        Ok(poll_fn(|cx| Pin::new(&mut *self).poll_shutdown(cx)).await?)
+    }
+
+
+    /// Abort sending on the QUIC stream. Manager resets the stream with
+    /// application error `code`, and data not yet delivered to the peer may be
+    /// discarded.
+    pub async fn reset(&mut self, code: u64) -> Result<()> {
+        self.outlen = None;
+        if !self.shared.lock().receiver.close_write(self.stream_id) {
+            return Ok(());
+        }
+        self.shared.send_frame(Frame::Reset { stream_id: self.stream_id, code }).await
+    }
+
+
+    /// Get statistics of the QUIC connection used by this stream.
+    pub async fn stats(&mut self) -> Result<ConnectionStats> {
+        self.shared.stats().await
+    }
+
+
+    /// Wait until manager has granted credit for sending data. Returns the
+    /// number of bytes that can be sent in one DATA frame.
+    fn poll_credit(&self, inner: &mut Inner, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        loop {
+            inner.receiver.check_write(self.stream_id)?;
+            let credit = inner.receiver.credit(self.stream_id);
+            if credit > 0 {
+                return Poll::Ready(Ok(credit));
+            }
+            if ready!(self.shared.poll_fill(inner, cx))? == 0 {
+                return Poll::Ready(Err(Receiver::closed_error()));
+            }
+        }
+    }
+}
+
+
+impl AsyncRead for OwnedReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut inner = self.shared.lock();
+        let id = self.stream_id;
+        inner.receiver.check_read(id)?;
+        while !inner.receiver.has_data(id) && !inner.receiver.is_eof(id) {
+            // Manager does not deliver more data until it gets the credit.
+            self.poll_send_credit(&mut inner, cx)?;
+            ready!(self.shared.poll_fill(&mut inner, cx))?;
+            inner.receiver.check_read(id)?;
+        }
+        let n = inner.receiver.read_data(id, buf.initialize_unfilled());
+        buf.advance(n);
+        if let Some(bytes) = inner.receiver.take_read_credit(id) {
+            inner.queue_frame(&Frame::Credit { stream_id: id, bytes });
+        }
+        self.poll_send_credit(&mut inner, cx)?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+
+impl AsyncWrite for OwnedWriteHalf {
+    /// Write bytes to QUIC connection. If the previous call returned
+    /// `Poll::Pending` after taking some of the data, that write is completed
+    /// first, and its length is returned.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut inner = this.shared.lock();
+        let outlen = match this.outlen {
+            Some(n) => n,
+            None => {
+                if buf.is_empty() {
+                    return Poll::Ready(Ok(0));
+                }
+                let credit = ready!(this.poll_credit(&mut inner, cx))?;
+                let (msg, n) = data_message(this.stream_id, buf, credit);
+                inner.receiver.use_credit(this.stream_id, n);
+                inner.queue(&msg);
+                this.outlen = Some(n);
+                n
+            },
+        };
+        ready!(this.shared.poll_send(&mut inner, cx))?;
+        this.outlen = None;
+        Poll::Ready(Ok(outlen))
+    }
+
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut inner = self.shared.lock();
+        self.shared.poll_send(&mut inner, cx)
+    }
+
+
+    /// Send FIN on the QUIC stream. Data can still be read after shutdown.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut inner = self.shared.lock();
+        if inner.receiver.close_write(self.stream_id) {
+            inner.queue(&fin_message(self.stream_id));
+        }
+        self.shared.poll_send(&mut inner, cx)
+    }
+}
+
+
+impl Drop for OwnedReadHalf {
+    fn drop(&mut self) {
+        let mut inner = self.shared.lock();
+        let finished = inner.receiver.peer_finished(self.stream_id);
+        if inner.receiver.close_read(self.stream_id) && !finished {
+            inner.queue_frame(&Frame::StopSending {
+                stream_id: self.stream_id,
+                code: CLIENT_GONE_ERROR_CODE,
+            });
+            inner.try_send();
+        }
+    }
+}
+
+
+impl Drop for OwnedWriteHalf {
+    fn drop(&mut self) {
+        let mut inner = self.shared.lock();
+        if inner.receiver.close_write(self.stream_id) {
+            inner.queue_frame(&Frame::Reset {
+                stream_id: self.stream_id,
+                code: CLIENT_GONE_ERROR_CODE,
+            });
+            inner.try_send();
+        }
+    }
+}
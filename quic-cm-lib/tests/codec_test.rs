@@ -0,0 +1,263 @@
+use std::time::Duration;
+
+use quic_cm::{
+    Error,
+    common::{get_varint, put_varint},
+    common::codec::{
+        ConnectionStats, Frame, FrameDecoder, MAX_STRING_LEN, PROTOCOL_VERSION,
+        negotiate_version,
+    },
+};
+
+
+fn sample_frames() -> Vec<Frame> {
+    vec![
+        Frame::Hello { version: PROTOCOL_VERSION },
+        Frame::Connect {
+            address: "127.0.0.1:7878".to_string(),
+            app_proto: "test".to_string(),
+            server_name: Some("quic.tech".to_string()),
+            identity: Some("alice".to_string()),
+            early_data: true,
+            congestion_control: Some("bbr2".to_string()),
+        },
+        Frame::ConnectOk { early_data: true, congestion_control: Some("cubic".to_string()) },
+        Frame::Data { stream_id: 4, data: b"Hello, world".to_vec() },
+        Frame::Credit { stream_id: 4, bytes: 65536 },
+        Frame::Fin { stream_id: 4 },
+        Frame::Reset { stream_id: 8, code: 0x1234 },
+        Frame::Error(Error::Dns("no such host".to_string())),
+        Frame::StatsRequest,
+        Frame::Stats(ConnectionStats {
+            rtt: Duration::from_micros(2500),
+            cwnd: 13500,
+            sent: 10,
+            congestion_control: "cubic".to_string(),
+            ..Default::default()
+        }),
+        Frame::StopSending { stream_id: 8, code: 0x5678 },
+        Frame::Open { bidi: false },
+        Frame::Opened { stream_id: 2 },
+        Frame::NewStream { stream_id: 3 },
+        Frame::Accept { bidi: true },
+        Frame::DatagramOpen,
+        Frame::DatagramOpened { flow_id: 7 },
+        Frame::Datagram { data: b"ping".to_vec() },
+        Frame::EarlyData { accepted: true },
+    ]
+}
+
+
+fn assert_sample_frames(frames: &[Frame]) {
+    assert_eq!(frames.len(), 19);
+    assert!(matches!(frames[0], Frame::Hello { version: PROTOCOL_VERSION }));
+    assert!(matches!(&frames[1],
+        Frame::Connect {
+            address, app_proto, server_name, identity, early_data: true, congestion_control,
+        }
+        if address == "127.0.0.1:7878" && app_proto == "test"
+            && server_name.as_deref() == Some("quic.tech")
+            && identity.as_deref() == Some("alice")
+            && congestion_control.as_deref() == Some("bbr2")));
+    assert!(matches!(&frames[2], Frame::ConnectOk { early_data: true, congestion_control }
+        if congestion_control.as_deref() == Some("cubic")));
+    assert!(matches!(&frames[3], Frame::Data { stream_id: 4, data } if data == b"Hello, world"));
+    assert!(matches!(frames[4], Frame::Credit { stream_id: 4, bytes: 65536 }));
+    assert!(matches!(frames[5], Frame::Fin { stream_id: 4 }));
+    assert!(matches!(frames[6], Frame::Reset { stream_id: 8, code: 0x1234 }));
+    assert!(matches!(&frames[7], Frame::Error(Error::Dns(m)) if m == "no such host"));
+    assert!(matches!(frames[8], Frame::StatsRequest));
+    match &frames[9] {
+        Frame::Stats(stats) => {
+            assert_eq!(stats.rtt, Duration::from_micros(2500));
+            assert_eq!(stats.cwnd, 13500);
+            assert_eq!(stats.sent, 10);
+            assert_eq!(stats.congestion_control, "cubic");
+        },
+        f => panic!("Unexpected frame: {:?}", f),
+    }
+    assert!(matches!(frames[10], Frame::StopSending { stream_id: 8, code: 0x5678 }));
+    assert!(matches!(frames[11], Frame::Open { bidi: false }));
+    assert!(matches!(frames[12], Frame::Opened { stream_id: 2 }));
+    assert!(matches!(frames[13], Frame::NewStream { stream_id: 3 }));
+    assert!(matches!(frames[14], Frame::Accept { bidi: true }));
+    assert!(matches!(frames[15], Frame::DatagramOpen));
+    assert!(matches!(frames[16], Frame::DatagramOpened { flow_id: 7 }));
+    assert!(matches!(&frames[17], Frame::Datagram { data } if data == b"ping"));
+    assert!(matches!(frames[18], Frame::EarlyData { accepted: true }));
+}
+
+
+#[test]
+fn test_coalesced_frames() {
+    let mut bytes = Vec::new();
+    for frame in sample_frames() {
+        frame.encode(&mut bytes);
+    }
+
+    let mut decoder = FrameDecoder::new();
+    decoder.feed(&bytes);
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.next_frame().unwrap() {
+        frames.push(frame);
+    }
+    assert_sample_frames(&frames);
+    assert!(!decoder.has_partial());
+}
+
+
+#[test]
+fn test_short_reads() {
+    let mut bytes = Vec::new();
+    for frame in sample_frames() {
+        frame.encode(&mut bytes);
+    }
+
+    // Feed one byte at a time, as if every read returned only one byte.
+    let mut decoder = FrameDecoder::new();
+    let mut frames = Vec::new();
+    for b in bytes {
+        decoder.feed(&[b]);
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            frames.push(frame);
+        }
+    }
+    assert_sample_frames(&frames);
+}
+
+
+#[test]
+fn test_invalid_frames() {
+    let mut decoder = FrameDecoder::new();
+    decoder.feed(&[0xff, 0, 0, 0, 0]);
+    assert!(matches!(decoder.next_frame(), Err(Error::Protocol(_))));
+
+    let mut decoder = FrameDecoder::new();
+    decoder.feed(&[0x04, 0xff, 0xff, 0xff, 0xff]);
+    assert!(matches!(decoder.next_frame(), Err(Error::Protocol(_))));
+
+    // HELLO with truncated version field
+    let mut decoder = FrameDecoder::new();
+    decoder.feed(&[0x01, 0, 0, 0, 1, 0]);
+    assert!(matches!(decoder.next_frame(), Err(Error::Protocol(_))));
+}
+
+
+#[test]
+fn test_connect_without_optional_fields() {
+    // CONNECT with only address and application protocol
+    let mut decoder = FrameDecoder::new();
+    decoder.feed(&[0x02, 0, 0, 0, 8, 0, 2, b'h', b'q', 0, 2, b'h', b'3']);
+    assert!(matches!(decoder.next_frame().unwrap(), Some(Frame::Connect {
+        server_name: None, identity: None, early_data: false, congestion_control: None, ..
+    })));
+
+    // CONNECT_OK without payload, and with early data flag only
+    decoder.feed(&[0x03, 0, 0, 0, 0]);
+    assert!(matches!(decoder.next_frame().unwrap(), Some(Frame::ConnectOk {
+        early_data: false, congestion_control: None,
+    })));
+    decoder.feed(&[0x03, 0, 0, 0, 1, 1]);
+    assert!(matches!(decoder.next_frame().unwrap(), Some(Frame::ConnectOk {
+        early_data: true, congestion_control: None,
+    })));
+
+    let frame = Frame::Connect {
+        address: "hq".to_string(),
+        app_proto: "h3".to_string(),
+        server_name: None,
+        identity: None,
+        early_data: false,
+        congestion_control: None,
+    };
+    decoder.feed(&frame.to_bytes());
+    assert!(matches!(decoder.next_frame().unwrap(), Some(Frame::Connect { server_name: None, .. })));
+
+    // STATS without congestion control algorithm
+    let mut stats = vec![0x0a, 0, 0, 0, 64];
+    stats.extend_from_slice(&[0; 64]);
+    decoder.feed(&stats);
+    assert!(matches!(decoder.next_frame().unwrap(),
+        Some(Frame::Stats(stats)) if stats.congestion_control.is_empty()));
+}
+
+
+#[test]
+fn test_connect_of_earlier_revisions() {
+    // Payloads of CONNECT as sent by clients before each trailing field was
+    // added: server name, identity, early data flag.
+    fn string(s: &str) -> Vec<u8> {
+        let mut v = (s.len() as u16).to_be_bytes().to_vec();
+        v.extend_from_slice(s.as_bytes());
+        v
+    }
+    let mut payload = [string("example.com:443"), string("h3")].concat();
+    let mut revisions = Vec::new();
+    for field in [string("example.org"), string("client"), vec![1]] {
+        payload.extend_from_slice(&field);
+        revisions.push(payload.clone());
+    }
+
+    let mut decoder = FrameDecoder::new();
+    for (i, payload) in revisions.iter().enumerate() {
+        let mut bytes = vec![0x02];
+        bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(payload);
+        decoder.feed(&bytes);
+        match decoder.next_frame().unwrap() {
+            Some(Frame::Connect {
+                address, app_proto, server_name, identity, early_data, congestion_control,
+            }) => {
+                assert_eq!(address, "example.com:443");
+                assert_eq!(app_proto, "h3");
+                assert_eq!(server_name.as_deref(), Some("example.org"));
+                assert_eq!(identity.as_deref(), (i >= 1).then_some("client"));
+                assert_eq!(early_data, i >= 2);
+                assert_eq!(congestion_control, None);
+            }
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+    }
+    assert!(!decoder.has_partial());
+}
+
+
+#[test]
+#[should_panic(expected = "does not fit in frame")]
+fn test_too_long_string() {
+    Frame::Connect {
+        address: "a".repeat(MAX_STRING_LEN + 1),
+        app_proto: "h3".to_string(),
+        server_name: None,
+        identity: None,
+        early_data: false,
+        congestion_control: None,
+    }.to_bytes();
+}
+
+
+#[test]
+fn test_version_negotiation() {
+    assert_eq!(negotiate_version(PROTOCOL_VERSION).unwrap(), PROTOCOL_VERSION);
+    assert_eq!(negotiate_version(PROTOCOL_VERSION + 1).unwrap(), PROTOCOL_VERSION);
+    assert!(negotiate_version(0).is_err());
+    assert!(negotiate_version(1).is_err());
+    assert!(negotiate_version(2).is_err());
+    assert!(negotiate_version(3).is_err());
+}
+
+
+#[test]
+fn test_varint() {
+    for (v, len) in [(0, 1), (63, 1), (64, 2), (16383, 2), (16384, 4), (1 << 30, 8)] {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, v);
+        assert_eq!(buf.len(), len);
+        buf.push(0xff);
+        assert_eq!(get_varint(&buf), Some((v, len)));
+    }
+    // Example from RFC 9000, appendix A.1
+    assert_eq!(get_varint(&[0x7b, 0xbd]), Some((15293, 2)));
+    assert_eq!(get_varint(&[0x7b]), None);
+    assert_eq!(get_varint(&[]), None);
+}
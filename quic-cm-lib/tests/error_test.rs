@@ -0,0 +1,51 @@
+use std::io;
+
+use quic_cm::Error;
+
+
+#[test]
+fn test_error_wire_roundtrip() {
+    let err = Error::from_wire(&Error::StreamReset(42).to_wire());
+    assert!(matches!(err, Error::StreamReset(42)));
+
+    let err = Error::from_wire(&Error::StreamStopped(43).to_wire());
+    assert!(matches!(err, Error::StreamStopped(43)));
+
+    let err = Error::from_wire(&Error::DatagramUnsupported.to_wire());
+    assert!(matches!(err, Error::DatagramUnsupported));
+
+    let err = Error::from_wire(&Error::PermissionDenied("identity".to_string()).to_wire());
+    assert!(matches!(err, Error::PermissionDenied(m) if m == "identity"));
+
+    let closed = Error::ConnectionClosed { code: 0x0a, reason: "going away".to_string() };
+    match Error::from_wire(&closed.to_wire()) {
+        Error::ConnectionClosed { code, reason } => {
+            assert_eq!(code, 0x0a);
+            assert_eq!(reason, "going away");
+        },
+        e => panic!("Unexpected error: {}", e),
+    }
+
+    let err = Error::from_wire(&Error::Dns("no such host".to_string()).to_wire());
+    assert!(matches!(err, Error::Dns(m) if m == "no such host"));
+}
+
+
+#[test]
+fn test_error_through_io_error() {
+    let ioerr: io::Error = Error::StreamReset(7).into();
+    assert_eq!(ioerr.kind(), io::ErrorKind::ConnectionReset);
+    assert!(matches!(Error::from(ioerr), Error::StreamReset(7)));
+
+    let err = Error::from(io::Error::from(io::ErrorKind::BrokenPipe));
+    assert!(matches!(err, Error::Io(e) if e.kind() == io::ErrorKind::BrokenPipe));
+}
+
+
+#[test]
+fn test_too_long_address() {
+    // Rejected before contacting manager, so this does not need one running
+    let address = "a".repeat(70000);
+    let err = quic_cm::blocking::QuicClient::connect(&address, "h3").err().unwrap();
+    assert!(matches!(err, Error::Io(e) if e.kind() == io::ErrorKind::InvalidInput));
+}
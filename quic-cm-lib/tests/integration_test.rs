@@ -1,5 +1,7 @@
 use std::{
     fs::remove_file,
+    path::Path,
+    io::{Read, Write},
     process::{Command, Child},
     sync::{
         Arc,
@@ -9,20 +11,38 @@ use std::{
     thread,
 };
 
-use tokio::time::sleep;
-use quic_cm::QuicClient;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{sleep, timeout},
+};
+use quic_cm::{ConnectOptions, Error, QuicClient, QuicSession, blocking};
 
 mod server;
 use crate::server::server;
 
 async fn start_manager() -> Child {
     Command::new("cargo")
-        .args(&["run", "--manifest-path", "../quic-cm-manager/Cargo.toml"])
+        // Test server certificate is not signed by a trusted CA.
+        .args(["run", "--manifest-path", "../quic-cm-manager/Cargo.toml",
+               "--", "--tls-no-verify", "--config", "tests/manager.toml"])
         .spawn()
         .expect("failed to start server")
 }
 
 
+/// Wait until manager has created its control socket. Manager is built on
+/// the first run, which can take a while.
+async fn wait_for_manager() {
+    for _ in 0..3000 {
+        if Path::new("/tmp/qcm-control").exists() {
+            return;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("Manager did not start");
+}
+
+
 async fn stop_manager(mut manager: Child) {
     manager.kill().expect("failed to kill server");
     manager.wait().expect("failed to wait on server");
@@ -42,12 +62,157 @@ async fn test_connection() {
     });
 
     let manager = start_manager().await;
-    sleep(Duration::from_secs(1)).await;
+    wait_for_manager().await;
 
     let client = QuicClient::connect("127.0.0.1:7878", "test").await;
     assert!(client.is_ok());
+    let mut client = client.unwrap();
+    assert!(client.write_all(b"Hello\n").await.is_ok());
+    assert!(client.stats().await.is_ok_and(|s| s.sent > 0));
+    assert!(client.shutdown_write().await.is_ok());
+    assert!(client.write_all(b"After shutdown\n").await.is_err());
+    // Different application protocol gets a connection of its own, which the
+    // server rejects, without affecting the first connection.
     let client2 = QuicClient::connect("127.0.0.1:7878", "test2").await;
-    assert!(client2.is_err());
+    assert!(matches!(client2, Err(Error::AlpnMismatch(_))));
+    assert!(client.stats().await.is_ok());
+
+    let blocking = tokio::task::spawn_blocking(|| -> quic_cm::Result<()> {
+        let mut client = blocking::QuicClient::connect("127.0.0.1:7878", "test")?;
+        client.write_all(b"Hello from blocking client\n")?;
+        client.shutdown_write()?;
+
+        let mut uni = blocking::QuicClient::connect_uni("127.0.0.1:7878", "test")?;
+        assert_eq!(uni.stream_id() % 4, 2);
+        uni.write_all(b"Hello from blocking uni client\n")?;
+        assert_eq!(uni.read(&mut [0; 16])?, 0);
+        uni.shutdown_write()
+    }).await.unwrap();
+    assert!(blocking.is_ok());
+
+    let client = QuicClient::connect("127.0.0.1:7878", "test").await.unwrap();
+    let (read_half, mut write_half) = client.into_split();
+    let writer = tokio::spawn(async move {
+        write_half.write_all(b"Hello from write half\n").await?;
+        write_half.shutdown_write().await
+    });
+    assert!(writer.await.unwrap().is_ok());
+    drop(read_half);
+
+    let session = QuicSession::connect("127.0.0.1:7878", "test").await.unwrap();
+    let mut stream1 = session.open_bidi().await.unwrap();
+    let mut stream2 = session.open_bidi().await.unwrap();
+    assert_ne!(stream1.stream_id(), stream2.stream_id());
+    assert!(stream1.write_all(b"Hello from stream 1\n").await.is_ok());
+    assert!(stream2.write_all(b"Hello from stream 2\n").await.is_ok());
+    assert!(stream1.shutdown_write().await.is_ok());
+    assert!(stream2.shutdown_write().await.is_ok());
+    let mut uni = session.open_uni().await.unwrap();
+    assert_eq!(uni.stream_id() % 4, 2);
+    assert!(uni.write_all(b"Hello from uni stream\n").await.is_ok());
+    assert!(uni.shutdown_write().await.is_ok());
+    // Default port is used when address has none, and the connection is
+    // shared with the clients above.
+    let session2 = QuicSession::connect("127.0.0.1", "test").await.unwrap();
+    let stats = session.stats().await.unwrap();
+    assert!(session2.stats().await.unwrap().sent >= stats.sent);
+    assert_eq!(stats.congestion_control, "cubic");
+    // Clients that connect at the same time are all accepted.
+    let concurrent = timeout(Duration::from_secs(10), async {
+        tokio::join!(
+            QuicSession::connect("127.0.0.1", "test"),
+            QuicSession::connect("127.0.0.1", "test"),
+            QuicSession::connect("127.0.0.1", "test"),
+        )
+    }).await;
+    assert!(concurrent.is_ok_and(|(a, b, c)| a.is_ok() && b.is_ok() && c.is_ok()));
+    // Client joining the shared connection keeps its algorithm.
+    let options = ConnectOptions::new().congestion_control("bbr2");
+    let session5 = QuicSession::connect_with("127.0.0.1", "test", &options).await.unwrap();
+    assert_eq!(session5.congestion_control().as_deref(), Some("cubic"));
+    assert_eq!(session5.stats().await.unwrap().congestion_control, "cubic");
+    let options = ConnectOptions::new().congestion_control("vegas");
+    let res = QuicSession::connect_with("127.0.0.1", "test", &options).await;
+    assert!(matches!(res, Err(Error::Protocol(_))));
+    // Connection was established before the session, so no early data.
+    let options = ConnectOptions::new().early_data(true);
+    let session4 = QuicSession::connect_with("127.0.0.1:7878", "test", &options).await.unwrap();
+    assert_eq!(session4.early_data_accepted().await.unwrap(), None);
+    // Server name given by the client makes a connection of its own, which
+    // uses the requested algorithm.
+    let options = ConnectOptions::new().server_name("quic.tech").congestion_control("bbr2");
+    let session3 = QuicSession::connect_with("127.0.0.1", "test", &options).await.unwrap();
+    assert_eq!(session3.congestion_control().as_deref(), Some("bbr2"));
+    let stats3 = session3.stats().await.unwrap();
+    assert!(stats3.sent < stats.sent);
+    assert_eq!(stats3.congestion_control, "bbr2");
+    // No client identities are configured in manager.
+    let options = ConnectOptions::new().identity("nobody");
+    let res = QuicSession::connect_with("127.0.0.1", "test", &options).await;
+    assert!(matches!(res, Err(Error::PermissionDenied(_))));
+    // Streams that the server opens are given to the sessions that accept
+    // streams of their kind. Test server opens one of each kind when asked.
+    // Session 7 accepts unidirectional streams before the server opens them,
+    // which the round trip of STATS confirms, but does not get the
+    // bidirectional one.
+    let session6 = QuicSession::connect("127.0.0.1", "test").await.unwrap();
+    let session7 = QuicSession::connect("127.0.0.1", "test").await.unwrap();
+    let accepted = timeout(Duration::from_secs(10), async {
+        tokio::join!(
+            session7.accept_uni(),
+            async {
+                session7.stats().await?;
+                let mut push = session6.open_bidi().await?;
+                push.write_all(b"push\n").await?;
+                session6.accept_bidi().await
+            },
+        )
+    }).await;
+    let (uni, bidi) = accepted.unwrap();
+    let mut bidi = bidi.unwrap();
+    assert_eq!(bidi.stream_id() % 4, 1);
+    let mut data = Vec::new();
+    assert!(bidi.read_to_end(&mut data).await.is_ok());
+    assert_eq!(data, b"Hello from server bidi stream\n");
+    let mut uni = uni.unwrap();
+    assert_eq!(uni.stream_id() % 4, 3);
+    data.clear();
+    assert!(uni.read_to_end(&mut data).await.is_ok());
+    assert_eq!(data, b"Hello from server uni stream\n");
+
+    // Test server echoes the rest of a stream that starts with "echo" line,
+    // and finishes when the client does. More than the receive window is
+    // echoed, so the session must grant credit to manager as it reads.
+    let (mut echo_read, mut echo_write) = session.open_bidi().await.unwrap().into_split();
+    let sent: Vec<u8> = (0..3_000_000u32).map(|i| i as u8).collect();
+    let expected = sent.clone();
+    let writer = tokio::spawn(async move {
+        echo_write.write_all(b"echo\n").await?;
+        echo_write.write_all(&sent).await?;
+        echo_write.shutdown_write().await
+    });
+    let mut data = Vec::new();
+    assert!(echo_read.read_to_end(&mut data).await.is_ok());
+    assert!(writer.await.unwrap().is_ok());
+    assert_eq!(data.len(), expected.len());
+    assert!(data == expected);
+    assert_eq!(echo_read.read(&mut [0; 16]).await.unwrap(), 0);
+
+    // Test server resets a stream that starts with "reset" line.
+    let mut reset = session.open_bidi().await.unwrap();
+    assert!(reset.write_all(b"reset\n").await.is_ok());
+    let err = reset.read(&mut [0; 16]).await.unwrap_err();
+    assert!(matches!(Error::from(err), Error::StreamReset(42)));
+
+    // Test server echoes datagrams, and manager delivers them to the flow of
+    // the session that sent them.
+    let flow = session.datagram_flow().await.unwrap();
+    assert_ne!(session2.datagram_flow().await.unwrap(), flow);
+    assert!(session.send_datagram(b"ping").await.is_ok());
+    let datagram = timeout(Duration::from_secs(5), session.recv_datagram()).await;
+    assert_eq!(datagram.unwrap().unwrap(), b"ping");
+    // Datagrams are disabled in manager configuration for quic.tech.
+    assert!(matches!(session3.datagram_flow().await, Err(Error::DatagramUnsupported)));
 
     stop_manager(manager).await;
     remove_file("/tmp/qcm-control").unwrap();  // TODO: terminate manager properly by signal
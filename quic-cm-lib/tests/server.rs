@@ -35,14 +35,20 @@ pub fn set_quic_config() -> Config {
     config.set_max_idle_timeout(50000);
     config.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
     config.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
-    config.set_initial_max_data(10_000_000);
-    config.set_initial_max_stream_data_bidi_local(1_000_000);
-    config.set_initial_max_stream_data_bidi_remote(1_000_000);
-    config.set_initial_max_stream_data_uni(1_000_000);
+    // Keep the data in flight from the client within the default socket
+    // receive buffer, so that the server does not drop packets in bursts.
+    // quiche keeps the connection window 1.5 times the stream window.
+    config.set_initial_max_data(100_000);
+    config.set_max_connection_window(100_000);
+    config.set_initial_max_stream_data_bidi_local(64_000);
+    config.set_initial_max_stream_data_bidi_remote(64_000);
+    config.set_initial_max_stream_data_uni(64_000);
+    config.set_max_stream_window(64_000);
     config.set_initial_max_streams_bidi(100);
     config.set_initial_max_streams_uni(100);
     config.set_disable_active_migration(true);
     config.enable_early_data();
+    config.enable_dgram(true, 1000, 1000);
 
     config
 }
@@ -53,10 +59,7 @@ pub fn create_socket(peer_addr: SocketAddr, local_port: u16) -> UdpSocket {
         std::net::SocketAddr::V6(_) => "[::]",
     };
     let addr = format!("{}:{}", bind_addr, local_port);
-    let socket =
-        UdpSocket::bind(addr.parse().unwrap()).unwrap();
-
-    socket
+    UdpSocket::bind(addr.parse().unwrap()).unwrap()
 }
 
 
@@ -82,12 +85,16 @@ pub fn set_mio_sources(socket: &mut UdpSocket, signals: &mut Signals) -> Poll {
 struct PartialResponse {
     body: Vec<u8>,
     written: usize,
+    fin: bool,
 }
 
 pub struct SockState {
     pub conn: Connection,
     partial_responses: HashMap<u64, PartialResponse>,
     streams: HashSet<u64>,  // currently active stream IDs
+    echo_streams: HashSet<u64>,  // streams that are echoed back to the client
+    next_bidi: u64,  // next server-initiated bidirectional stream ID
+    next_uni: u64,  // next server-initiated unidirectional stream ID
 }
 
 impl SockState {
@@ -96,6 +103,9 @@ impl SockState {
             conn,
             partial_responses: HashMap::new(),
             streams: HashSet::new(),
+            echo_streams: HashSet::new(),
+            next_bidi: 1,
+            next_uni: 3,
         }
     }
 
@@ -118,10 +128,10 @@ impl SockState {
                         if multistream {
                             let streams: Vec<u64> = self.streams.iter().cloned().collect();
                             for stream in streams {
-                                self.write(stream, &inbuf[..n]);
+                                self.write(stream, &inbuf[..n], false);
                             }
                         } else {
-                            self.write(stream_id, &inbuf[..n]);
+                            self.write(stream_id, &inbuf[..n], false);
                         }
                     }
                     Err(e) => println!("Error reading from stdin: {:?}", e),
@@ -146,26 +156,44 @@ impl SockState {
     
         // Process all readable streams.
         for s in self.conn.readable() {
-            while let Ok((read, _fin)) =
+            while let Ok((read, fin)) =
                 self.conn.stream_recv(s, &mut buf)
             {
                 //debug!("{} received {} bytes", self.conn.trace_id(), read);
 
-                self.streams.insert(s);
-                let stream_buf = &buf[..read];
+                let new = self.streams.insert(s);
+                let mut stream_buf = &buf[..read];
                 /*debug!(
                     "{} stream {} has {} bytes (fin? {})",
                     self.conn.trace_id(), s, stream_buf.len(), fin
                 );*/
+                // Stream that starts with "echo" line gets the rest of its
+                // data back, and FIN when the client finishes.
+                if new && stream_buf.starts_with(b"echo\n") {
+                    self.echo_streams.insert(s);
+                    stream_buf = &stream_buf[5..];
+                }
+                if self.echo_streams.contains(&s) {
+                    self.echo(s, stream_buf, fin);
+                    continue;
+                }
                 // TODO: handle closing streams
 
                 let str = String::from_utf8(stream_buf.to_vec()).unwrap();
                 //debug!("from stream {}: ", s);
                 print!("{}", str);
+                match str.as_str() {
+                    "push\n" => self.push_streams(),
+                    "reset\n" => {
+                        self.conn.stream_shutdown(s, quiche::Shutdown::Write, 42).ok();
+                    },
+                    _ => (),
+                }
             }
             //let wbytes = [0; 5];
             //self.write(s, &wbytes);
         }
+        self.echo_datagrams();
     }
 
 
@@ -180,7 +208,7 @@ impl SockState {
         let resp = self.partial_responses.get_mut(&stream_id).unwrap();
         let body = &resp.body[resp.written..];
 
-        let written = match self.conn.stream_send(stream_id, body, false) {
+        let written = match self.conn.stream_send(stream_id, body, resp.fin) {
             Ok(v) => v,
 
             Err(quiche::Error::Done) => 0,
@@ -201,8 +229,42 @@ impl SockState {
     }
 
 
-    fn write(&mut self, stream_id: u64, buf: &[u8]) {
-        let written = match self.conn.stream_send(stream_id, buf, false) {
+    /// Send data received on echoed stream back to the client, after data
+    /// that is still waiting to be sent.
+    fn echo(&mut self, stream_id: u64, buf: &[u8], fin: bool) {
+        match self.partial_responses.get_mut(&stream_id) {
+            Some(resp) => {
+                resp.body.extend_from_slice(buf);
+                resp.fin |= fin;
+            },
+            None => self.write(stream_id, buf, fin),
+        }
+    }
+
+
+    /// Echo datagrams back to the client.
+    fn echo_datagrams(&mut self) {
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        while let Ok(len) = self.conn.dgram_recv(&mut buf) {
+            self.conn.dgram_send(&buf[..len]).ok();
+        }
+    }
+
+
+    /// Open a bidirectional and a unidirectional stream to the client, and
+    /// send a line with FIN on both.
+    fn push_streams(&mut self) {
+        let bidi = self.next_bidi;
+        let uni = self.next_uni;
+        self.next_bidi += 4;
+        self.next_uni += 4;
+        self.write(bidi, b"Hello from server bidi stream\n", true);
+        self.write(uni, b"Hello from server uni stream\n", true);
+    }
+
+
+    fn write(&mut self, stream_id: u64, buf: &[u8], fin: bool) {
+        let written = match self.conn.stream_send(stream_id, buf, fin) {
             Ok(v) => v,
     
             Err(quiche::Error::Done) => 0,
@@ -215,7 +277,7 @@ impl SockState {
     
         //debug!("written: {} ; buf.len: {}", written, buf.len());
         if written < buf.len() {
-            let response = PartialResponse { body: buf.to_vec(), written };
+            let response = PartialResponse { body: buf.to_vec(), written, fin };
             self.partial_responses.insert(stream_id, response);
         }
     }
@@ -261,13 +323,18 @@ pub fn server(terminate_signal: Arc<AtomicBool>) {
             println!("Termination signal received. Shutting down server...");
             break;
         }
-        // Find the shorter timeout from all the active connections.
-        //
-        // TODO: use event loop that properly supports timers
-        //let timeout = clients.values().filter_map(|c| c.sockstate.conn.timeout()).min();
-        let timeout = Duration::new(1, 0);
+        // Find the shorter timeout from all the active connections, but wake
+        // up at least once a second to check for termination.
+        let timeout = clients.values()
+            .filter_map(|c| c.sockstate.conn.timeout())
+            .fold(Duration::new(1, 0), Duration::min);
         poll.poll(&mut events, Some(timeout)).unwrap();
 
+        // Timers of connections are handled also when packets keep arriving,
+        // so that lost packets are retransmitted. quiche ignores timers that
+        // have not expired.
+        clients.values_mut().for_each(|c| c.sockstate.conn.on_timeout());
+
         // Check for signals also when there are no clients (TODO: this whole loop needs refactoring)
         if clients.is_empty() {
             for event in &events {
@@ -301,8 +368,6 @@ pub fn server(terminate_signal: Arc<AtomicBool>) {
                     break;
                 }
 
-                clients.values_mut().for_each(|c| c.sockstate.conn.on_timeout());
-
                 break 'read;
             }
 
@@ -480,7 +545,6 @@ pub fn server(terminate_signal: Arc<AtomicBool>) {
                 for stream_id in client.sockstate.conn.writable() {
                     client.sockstate.handle_writable(stream_id);
                 }
-                assert!(true, "established");
                 client.sockstate.handle_established();
             }
         }
@@ -1,60 +1,277 @@
 use std::{
-    io::{Read, Write},
+    collections::HashMap,
+    io::{self, Read, Write},
     os::unix::net::UnixStream,
 };
 
 use mio::Token;
-use quic_cm::common::write_data_header_sync;
+use quic_cm::{
+    Error,
+    common::{RECV_WINDOW, is_bidi},
+    common::codec::{
+        FRAME_HEADER_LEN, MAX_FRAME_LEN, ConnectionStats, Frame, FrameDecoder, error_frame,
+    },
+};
 
 use crate::mio_tokens::TokenManager;
 
+/// Number of bytes that client can send on a stream before manager has passed
+/// them to the QUIC stream.
+const WRITE_WINDOW: u64 = 1_000_000;
+
 
 /// QUIC-CM client is a Unix domain stream socket endpoint that the actual client
-/// application uses to connect QUIC-CM. Each client corresponds to one stream
-/// in a QUIC connection to a server. QUIC-CM library is available for the client
-/// implementations on operating with the QUIC connection and Unix domain socket.
+/// application uses to connect QUIC-CM. Each client is a session that can have
+/// several streams in a QUIC connection to a server. QUIC-CM library is
+/// available for the client implementations on operating with the QUIC
+/// connection and Unix domain socket.
+///
+/// The socket is nonblocking. Frames to the client are buffered when the socket
+/// buffer is full, and the client is congested until they are written. Data from
+/// the client is held until the QUIC stream accepts it. Client gets more credit
+/// for writing as the data is passed to the stream, so the held data is limited
+/// by the write window of each stream. Data from the QUIC stream is delivered
+/// to the client up to the receive window of the stream, which the client
+/// extends with CREDIT frames as the application reads.
 pub struct Client {
     socket: UnixStream,
     token: Token,
-    readbuf: [u8; 65535],
-    readn: usize,
+    decoder: FrameDecoder,
+    /// Streams of this client, key is QUIC stream ID.
+    streams: HashMap<u64, ClientStream>,
+    /// Frames to client that did not fit in the socket buffer.
+    writebuf: Vec<u8>,
+    /// Datagram flow assigned to client.
+    flow: Option<u64>,
+    /// Client has been told that it is connected in 0-RTT, and waits to know
+    /// whether early data was accepted.
+    in_early_data: bool,
+    /// Unix socket has been closed by client.
+    closed: bool,
+}
+
+
+/// Stream of a client, as seen by manager.
+#[derive(Default)]
+struct ClientStream {
+    /// Data from client that is not yet sent to QUIC stream.
+    readbuf: Vec<u8>,
+    /// Client has sent FIN.
+    fin: bool,
+    /// FIN has been passed to the QUIC stream.
+    fin_fetched: bool,
+    /// Client has reset its sending direction.
+    reset: bool,
+    /// Number of bytes that client may still send.
+    credit: u64,
+    /// Number of bytes passed to the QUIC stream, but not yet granted back to
+    /// client as credit.
+    consumed: u64,
+    /// Number of bytes that may still be delivered to client.
+    recv_credit: u64,
+    /// Receiving direction is done: FIN or RESET from peer is delivered to
+    /// client, or client has stopped reading.
+    recv_done: bool,
+}
+
+impl ClientStream {
+    fn has_pending(&self) -> bool {
+        !self.readbuf.is_empty() || (self.fin && !self.fin_fetched)
+    }
+
+
+    fn send_done(&self) -> bool {
+        self.fin_fetched || self.reset
+    }
+}
+
+
+/// Control message received from client application.
+pub enum ControlMsg {
+    /// Client asks to open bidirectional or unidirectional stream.
+    Open { bidi: bool },
+    /// Client accepts bidirectional or unidirectional streams opened by the
+    /// server.
+    Accept { bidi: bool },
+    /// Client asks for datagram flow.
+    DatagramOpen,
+    /// Client sends datagram.
+    Datagram(Vec<u8>),
+    /// Client has reset the stream with given application error code.
+    Reset { stream_id: u64, code: u64 },
+    /// Client asks peer to stop sending with given application error code.
+    StopSending { stream_id: u64, code: u64 },
+    /// Client has granted more receive credit on the stream.
+    Credit { stream_id: u64 },
+    /// Client asks for connection statistics.
+    Stats,
+    /// Unix socket was closed.
+    Closed,
 }
 
 
 impl Client {
+    /// Create client for socket that has completed HELLO and CONNECT exchange.
+    /// `decoder` holds bytes that client sent after CONNECT, if any. Socket
+    /// must be in nonblocking mode.
     pub fn new(
         socket: UnixStream,
         token: Token,
+        decoder: FrameDecoder,
     ) -> Client {
         Client {
             socket,
             token,
-            readbuf: [0; 65535],
-            readn: 0,
+            decoder,
+            streams: HashMap::new(),
+            writebuf: Vec::new(),
+            flow: None,
+            in_early_data: false,
+            closed: false,
         }
     }
 
 
-    pub fn send_ok(&mut self) {
-        let ok = *b"OKOK";
-        self.socket.write(&ok).unwrap();
+    fn send_frame(&mut self, frame: &Frame) {
+        frame.encode(&mut self.writebuf);
+        self.flush();
     }
 
 
-    pub fn send_error(&mut self, message: &str) {
-        let str = String::from("ERRO ") + message;
-        let err = str.as_bytes();
-        self.socket.write(&err).unwrap();
+    /// Write buffered frames to Unix socket, until socket buffer is full.
+    pub fn flush(&mut self) {
+        while !self.writebuf.is_empty() {
+            match self.socket.write(&self.writebuf) {
+                Ok(n) => {
+                    self.writebuf.drain(..n);
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Could not send frame to client: {}", e);
+                    self.writebuf.clear();
+                },
+            }
+        }
+    }
+
+
+    /// Returns true if frames are waiting for space in the socket buffer.
+    /// No more data should be read from the QUIC streams then.
+    pub fn is_congested(&self) -> bool {
+        !self.writebuf.is_empty()
+    }
+
+
+    /// Tell client that connection is established, and which congestion
+    /// control algorithm it uses.
+    pub fn send_ok(&mut self, congestion_control: &str) {
+        self.send_frame(&Frame::ConnectOk {
+            early_data: false,
+            congestion_control: Some(congestion_control.to_string()),
+        });
+    }
+
+
+    /// Tell client that it can start sending data in 0-RTT, before handshake
+    /// is complete.
+    pub fn send_early_ok(&mut self, congestion_control: &str) {
+        self.in_early_data = true;
+        self.send_frame(&Frame::ConnectOk {
+            early_data: true,
+            congestion_control: Some(congestion_control.to_string()),
+        });
+    }
+
+
+    /// Returns true if client is connected in 0-RTT and handshake has not
+    /// completed yet.
+    pub fn is_in_early_data(&self) -> bool {
+        self.in_early_data
+    }
+
+
+    /// Tell client connected in 0-RTT whether its early data was `accepted`.
+    pub fn early_data_done(&mut self, accepted: bool) {
+        self.in_early_data = false;
+        self.send_frame(&Frame::EarlyData { accepted });
+    }
+
+
+    /// Add stream that client requested with OPEN.
+    pub fn open_stream(&mut self, stream_id: u64, bidi: bool) {
+        self.add_stream(stream_id, Frame::Opened { stream_id }, true, bidi);
+    }
+
+
+    /// Give stream opened by the server to client.
+    pub fn new_stream(&mut self, stream_id: u64) {
+        self.add_stream(stream_id, Frame::NewStream { stream_id }, is_bidi(stream_id), true);
+    }
+
+
+    /// Add stream, announce it to client with `frame`, and grant the initial
+    /// write window if the stream has sending direction.
+    fn add_stream(&mut self, stream_id: u64, frame: Frame, send: bool, recv: bool) {
+        self.streams.insert(stream_id, ClientStream {
+            credit: if send { WRITE_WINDOW } else { 0 },
+            recv_credit: if recv { RECV_WINDOW } else { 0 },
+            // Stream without sending direction is finished from the start.
+            fin: !send,
+            fin_fetched: !send,
+            recv_done: !recv,
+            ..Default::default()
+        });
+        frame.encode(&mut self.writebuf);
+        if send {
+            Frame::Credit { stream_id, bytes: WRITE_WINDOW }.encode(&mut self.writebuf);
+        }
+        self.flush();
+    }
+
+
+    /// Tell client that peer has finished sending on the stream.
+    pub fn send_fin(&mut self, stream_id: u64) {
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.recv_done = true;
+        }
+        self.send_frame(&Frame::Fin { stream_id });
+    }
+
+
+    /// Tell client that peer has reset the stream.
+    pub fn send_reset(&mut self, stream_id: u64, code: u64) {
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.recv_done = true;
+        }
+        self.send_frame(&Frame::Reset { stream_id, code });
+    }
+
+
+    /// Tell client that peer has stopped reading the stream.
+    pub fn send_stop_sending(&mut self, stream_id: u64, code: u64) {
+        self.send_frame(&Frame::StopSending { stream_id, code });
+    }
+
+
+    pub fn send_stats(&mut self, stats: ConnectionStats) {
+        self.send_frame(&Frame::Stats(stats));
+    }
+
+
+    pub fn send_error(&mut self, err: &Error) {
+        self.writebuf.extend_from_slice(&error_frame(err));
+        self.flush();
     }
 
 
     /// Send error to a particular socket, and produce a log error.
     /// Can be used when Client instance is not available,
-    pub fn send_socket_error(socket: &mut UnixStream, message: &str) {
-        error!("{}", message);
-        let str = String::from("ERROR ") + message;
-        let err = str.as_bytes();
-        socket.write(&err).unwrap();
+    pub fn send_socket_error(socket: &mut UnixStream, err: &Error) {
+        error!("{}", err);
+        if let Err(e) = socket.write_all(&error_frame(err)) {
+            error!("Could not send error to client: {}", e);
+        }
     }
 
 
@@ -63,75 +280,229 @@ impl Client {
     }
 
 
-    pub fn deliver_data(&mut self, data: &Vec<u8>) -> Result<usize, String> {
-        let len: u32 = data.len().try_into().unwrap();
-        write_data_header_sync(&mut self.socket, len).unwrap();
-        match self.socket.write(&data) {
-            Ok(n) => Ok(n),
-            Err(e) => Err(format!("Writing to client Unix socket failed: {}", e)),
+    /// Deliver data from QUIC stream to client. Data must fit in the receive
+    /// credit of the stream.
+    pub fn deliver_data(&mut self, stream_id: u64, data: &[u8]) {
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.recv_credit -= data.len() as u64;
         }
+        self.send_frame(&Frame::Data { stream_id, data: data.to_vec() });
     }
 
 
-    pub fn get_token(&self) -> Token {
-        self.token
+    /// Returns number of bytes that can be delivered to client on the stream.
+    pub fn recv_credit(&self, stream_id: u64) -> u64 {
+        self.streams.get(&stream_id).map_or(0, |s| s.recv_credit)
     }
 
 
-    /// Process control message from Unix domain socket.
-    /// Returns number of bytes sent forward, or 0 if the Unix socket is closed
-    /// (most likely because the client application has terminated).
-    pub fn process_control_msg(&mut self) -> Result<usize, String> {
-        let mut cmd: [u8; 4] = [0; 4];
-        let n = match self.socket.read(&mut cmd) {
-            Ok(n) => n,
-            Err(e) => {
-                error!("Read from fifo failed: {}", e);
-                return Err(format!("Read from fifo failed: {}", e));
-            },
-        };
+    /// Assign datagram flow to client.
+    pub fn open_flow(&mut self, flow_id: u64) {
+        self.flow = Some(flow_id);
+        self.send_frame(&Frame::DatagramOpened { flow_id });
+    }
+
 
-        if n == 0 {
-            return Ok(0);
+    pub fn flow(&self) -> Option<u64> {
+        self.flow
+    }
+
+
+    /// Deliver datagram to client. Datagram is dropped if client is congested,
+    /// because datagrams are not worth buffering.
+    pub fn deliver_datagram(&mut self, data: &[u8]) {
+        if self.is_congested() {
+            debug!("Client is congested, dropping datagram of {} bytes", data.len());
+            return;
         }
-        if n < 4 {
-            return Err(format!("Could not read command from Fifo: {} bytes", n));
+        self.send_frame(&Frame::Datagram { data: data.to_vec() });
+    }
+
+
+    fn stream_mut(&mut self, stream_id: u64) -> Result<&mut ClientStream, Error> {
+        match self.streams.get_mut(&stream_id) {
+            Some(stream) => Ok(stream),
+            None => Err(Error::Protocol(format!("Unknown stream {}", stream_id))),
         }
+    }
 
-        let cmdstr = match String::from_utf8(cmd.to_vec()) {
-            Ok(s) => s,
-            Err(e) => return Err(format!("Invalid command: {}", e)),
+
+    /// Process control messages from Unix domain socket, until the socket would
+    /// block. Returns ControlMsg::Closed if the Unix socket is closed (most
+    /// likely because the client application has terminated).
+    pub fn process_control_msg(&mut self) -> Result<Vec<ControlMsg>, Error> {
+        let mut buf = [0; FRAME_HEADER_LEN + MAX_FRAME_LEN];
+        let mut msgs = Vec::new();
+        // Decoder may hold frames that were read along with CONNECT.
+        self.process_frames(&mut msgs)?;
+        while !self.closed {
+            let n = match self.socket.read(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Read from fifo failed: {}", e);
+                    return Err(Error::Io(e));
+                },
+            };
+            if n == 0 {
+                self.closed = true;
+                msgs.push(ControlMsg::Closed);
+                break;
+            }
+            debug!("Read {} bytes from control socket", n);
+            self.decoder.feed(&buf[..n]);
+            self.process_frames(&mut msgs)?;
+        }
+        Ok(msgs)
+    }
+
+
+    /// Process complete frames that have been read from the socket.
+    fn process_frames(&mut self, msgs: &mut Vec<ControlMsg>) -> Result<(), Error> {
+        while let Some(frame) = self.decoder.next_frame()? {
+            match frame {
+                Frame::Open { bidi } => msgs.push(ControlMsg::Open { bidi }),
+                Frame::Accept { bidi } => msgs.push(ControlMsg::Accept { bidi }),
+                Frame::DatagramOpen => msgs.push(ControlMsg::DatagramOpen),
+                Frame::Datagram { data } => msgs.push(ControlMsg::Datagram(data)),
+                Frame::Data { stream_id, data } => {
+                    let stream = self.stream_mut(stream_id)?;
+                    if data.len() as u64 > stream.credit {
+                        return Err(Error::Protocol(format!(
+                            "Client sent {} bytes with credit for {}", data.len(), stream.credit
+                        )));
+                    }
+                    stream.credit -= data.len() as u64;
+                    stream.readbuf.extend_from_slice(&data);
+                },
+                Frame::Fin { stream_id } => {
+                    debug!("Client finished sending on stream {}", stream_id);
+                    self.stream_mut(stream_id)?.fin = true;
+                },
+                Frame::Reset { stream_id, code } => {
+                    debug!("Client reset stream {} with code {}", stream_id, code);
+                    self.stream_mut(stream_id)?.reset = true;
+                    self.discard_pending(stream_id);
+                    msgs.push(ControlMsg::Reset { stream_id, code });
+                },
+                Frame::StopSending { stream_id, code } => {
+                    debug!("Client stopped reading stream {} with code {}", stream_id, code);
+                    self.stream_mut(stream_id)?.recv_done = true;
+                    msgs.push(ControlMsg::StopSending { stream_id, code });
+                },
+                Frame::Credit { stream_id, bytes } => {
+                    // Stream may already be removed, if the client read the
+                    // last data after manager delivered FIN.
+                    if let Some(stream) = self.streams.get_mut(&stream_id) {
+                        stream.recv_credit += bytes;
+                        msgs.push(ControlMsg::Credit { stream_id });
+                    }
+                },
+                Frame::StatsRequest => msgs.push(ControlMsg::Stats),
+                frame => {
+                    return Err(Error::Protocol(format!("Unexpected frame: {:?}", frame)));
+                },
+            }
+        }
+        Ok(())
+    }
+
+
+    /// Give client credit for `n` bytes that have left the buffer of a stream.
+    /// Credit is granted in batches, to avoid sending a CREDIT frame for every
+    /// write.
+    fn grant(&mut self, stream_id: u64, n: usize) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return,
         };
+        stream.consumed += n as u64;
+        if stream.consumed >= WRITE_WINDOW / 4 {
+            let bytes = stream.consumed;
+            stream.credit += bytes;
+            stream.consumed = 0;
+            self.send_frame(&Frame::Credit { stream_id, bytes });
+        }
+    }
 
-        match cmdstr.as_str() {
-            "DATA" => {
-                let mut lbuf: [u8; 4] = [0; 4];
-                let n = match self.socket.read(&mut lbuf) {
-                    Ok(n) => n,
-                    Err(e) => return Err(format!("Not valid bytes in DATA message: {}", e)), 
-                };
-                if n < 4 {
-                    return Err(format!("Could not read command from Fifo: {} bytes", n));
-                }
-
-                self.readn = match self.socket.read(&mut self.readbuf) {
-                    Ok(n) => n,
-                    Err(e) => {
-                        error!("Read from fifo failed: {}", e);
-                        return Err(format!("Read from fifo failed: {}", e));
-                    },
-                };
-                debug!("Read {} bytes from control socket", self.readn);
-                Ok(self.readn)
-            },
-            _ => Err(format!("Unknown command: {}", cmdstr)),
+
+    /// Returns IDs of all streams of this client.
+    pub fn stream_ids(&self) -> Vec<u64> {
+        self.streams.keys().copied().collect()
+    }
+
+
+    /// Returns IDs of streams that have data or FIN not yet passed to the QUIC
+    /// stream.
+    pub fn pending_streams(&self) -> Vec<u64> {
+        self.streams.iter()
+            .filter(|(_, stream)| stream.has_pending())
+            .map(|(stream_id, _)| *stream_id)
+            .collect()
+    }
+
+
+    /// Returns true if client has finished some stream, but not all of the
+    /// data is passed to the QUIC stream yet.
+    pub fn has_pending_fin(&self) -> bool {
+        self.streams.values().any(|stream| stream.fin && stream.has_pending())
+    }
+
+
+    /// Returns buffered data of a stream and whether FIN should be sent after
+    /// it.
+    pub fn pending_data(&self, stream_id: u64) -> (&[u8], bool) {
+        match self.streams.get(&stream_id) {
+            Some(stream) => (&stream.readbuf, stream.fin && !stream.fin_fetched),
+            None => (&[], false),
         }
     }
 
 
-    pub fn fetch_databuf(&mut self) -> (usize, &[u8]) {
-        let n = self.readn;
-        self.readn = 0;
-        (n, &self.readbuf)
+    /// Remove `n` bytes that QUIC stream has accepted from the buffer.
+    /// `fin` tells that FIN was passed to the stream along with them.
+    pub fn consume(&mut self, stream_id: u64, n: usize, fin: bool) {
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.readbuf.drain(..n);
+            stream.fin_fetched |= fin;
+        }
+        self.grant(stream_id, n);
+    }
+
+
+    /// Drop data that cannot be sent because the stream is no longer writable.
+    pub fn discard_pending(&mut self, stream_id: u64) {
+        let n = match self.streams.get_mut(&stream_id) {
+            Some(stream) => {
+                stream.fin_fetched = stream.fin;
+                std::mem::take(&mut stream.readbuf).len()
+            },
+            None => return,
+        };
+        self.grant(stream_id, n);
+    }
+
+
+    /// Returns true if client has finished or reset sending on the stream.
+    pub fn has_finished(&self, stream_id: u64) -> bool {
+        self.streams.get(&stream_id).is_some_and(|s| s.fin || s.reset)
+    }
+
+
+    /// Returns true if the stream has been closed in both directions.
+    pub fn is_stream_done(&self, stream_id: u64) -> bool {
+        self.streams.get(&stream_id).is_some_and(|s| s.send_done() && s.recv_done)
+    }
+
+
+    pub fn remove_stream(&mut self, stream_id: u64) {
+        self.streams.remove(&stream_id);
+    }
+
+
+    /// Returns true if client has closed the Unix socket.
+    pub fn is_closed(&self) -> bool {
+        self.closed
     }
 }
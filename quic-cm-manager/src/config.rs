@@ -0,0 +1,570 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::Parser;
+use serde::Deserialize;
+use quic_cm::common::QCM_CONTROL_SOCKET;
+
+use crate::{
+    destination::ConnectionKey,
+    pool::{PoolPolicy, SpillPolicy},
+    profile::Profile,
+    tls::TlsSettings,
+};
+
+
+/// Smallest UDP payload that QUIC allows.
+const MIN_UDP_PAYLOAD_SIZE: usize = 1200;
+
+/// Largest UDP payload that manager sends or receives. Buffers for QUIC
+/// packets are of this size.
+pub const MAX_UDP_PAYLOAD_SIZE: usize = 1500;
+
+
+/// QUIC connection manager.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Configuration file in TOML format.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Path of the control socket that clients connect to.
+    #[arg(long)]
+    socket: Option<PathBuf>,
+    /// Log level or env_logger filter, for example "info" or "quic_cm=debug".
+    #[arg(long)]
+    log_level: Option<String>,
+    /// File of trusted CA certificates, in addition to the system CAs.
+    #[arg(long)]
+    tls_ca_file: Option<String>,
+    /// Directory of trusted CA certificates.
+    #[arg(long)]
+    tls_ca_dir: Option<String>,
+    /// Do not verify server certificates.
+    #[arg(long)]
+    tls_no_verify: bool,
+    /// Directory where TLS sessions are stored for resumption.
+    #[arg(long)]
+    session_cache_dir: Option<PathBuf>,
+    /// Idle timeout of connections in milliseconds, 0 for none.
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+    /// Connection flow control window in bytes.
+    #[arg(long)]
+    max_data: Option<u64>,
+    /// Stream flow control window in bytes, for all kinds of streams.
+    #[arg(long)]
+    max_stream_data: Option<u64>,
+    /// Number of bidirectional streams that the server may open.
+    #[arg(long)]
+    max_streams_bidi: Option<u64>,
+    /// Number of unidirectional streams that the server may open.
+    #[arg(long)]
+    max_streams_uni: Option<u64>,
+    /// Largest UDP payload to send and receive, between 1200 and 1500.
+    #[arg(long)]
+    max_udp_payload: Option<usize>,
+}
+
+
+/// Contents of the configuration file. Everything is optional, and missing
+/// values have defaults.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub socket: Option<PathBuf>,
+    pub log_level: Option<String>,
+    pub session_cache_dir: Option<PathBuf>,
+    pub pool: PoolConfig,
+    pub transport: TransportParams,
+    pub tls: TlsConfig,
+    /// Transport profiles, in `[[profile]]` tables. Connection uses the first
+    /// profile that matches its destination.
+    #[serde(rename = "profile")]
+    pub profiles: Vec<ProfileConfig>,
+    /// Settings of particular destinations, in `[[destination]]` tables.
+    #[serde(rename = "destination")]
+    pub destinations: Vec<DestinationConfig>,
+}
+
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Name of the pool policy.
+    pub policy: Option<String>,
+    pub spill_queue_depth: Option<usize>,
+    pub spill_wait_ms: Option<u64>,
+    /// Number of connections to open to a destination before clients share
+    /// them.
+    pub min_connections: Option<usize>,
+}
+
+
+/// QUIC transport parameters and limits of connections.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportParams {
+    pub idle_timeout_ms: u64,
+    pub max_data: u64,
+    pub max_stream_data_bidi_local: u64,
+    pub max_stream_data_bidi_remote: u64,
+    pub max_stream_data_uni: u64,
+    pub max_streams_bidi: u64,
+    pub max_streams_uni: u64,
+    pub max_udp_payload_size: usize,
+    /// Offer QUIC datagrams to the server.
+    pub datagrams: bool,
+    /// Number of QUIC datagrams that quiche queues for sending and receiving.
+    pub datagram_queue_len: usize,
+    pub congestion_control: CongestionControl,
+    /// Use HyStart++ to leave slow start before losses.
+    pub hystart: bool,
+    /// Pace sending of packets over the round-trip time, instead of sending
+    /// them in bursts.
+    pub pacing: bool,
+    /// Interval of keepalive packets when connection has clients but is
+    /// otherwise idle, 0 disables keepalive.
+    pub keepalive_ms: u64,
+}
+
+
+/// Congestion control algorithm of connections.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CongestionControl {
+    Reno,
+    #[default]
+    Cubic,
+    Bbr,
+    Bbr2,
+}
+
+impl CongestionControl {
+    /// Parse algorithm name, as in configuration file.
+    pub fn from_name(name: &str) -> Option<CongestionControl> {
+        match name {
+            "reno" => Some(CongestionControl::Reno),
+            "cubic" => Some(CongestionControl::Cubic),
+            "bbr" => Some(CongestionControl::Bbr),
+            "bbr2" => Some(CongestionControl::Bbr2),
+            _ => None,
+        }
+    }
+
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CongestionControl::Reno => "reno",
+            CongestionControl::Cubic => "cubic",
+            CongestionControl::Bbr => "bbr",
+            CongestionControl::Bbr2 => "bbr2",
+        }
+    }
+
+
+    pub fn algorithm(&self) -> quiche::CongestionControlAlgorithm {
+        match self {
+            CongestionControl::Reno => quiche::CongestionControlAlgorithm::Reno,
+            CongestionControl::Cubic => quiche::CongestionControlAlgorithm::CUBIC,
+            CongestionControl::Bbr => quiche::CongestionControlAlgorithm::BBR,
+            CongestionControl::Bbr2 => quiche::CongestionControlAlgorithm::BBR2,
+        }
+    }
+}
+
+impl Default for TransportParams {
+    fn default() -> TransportParams {
+        TransportParams {
+            idle_timeout_ms: 50000,
+            max_data: 10_000_000,
+            max_stream_data_bidi_local: 1_000_000,
+            max_stream_data_bidi_remote: 1_000_000,
+            max_stream_data_uni: 1_000_000,
+            max_streams_bidi: 100,
+            max_streams_uni: 100,
+            max_udp_payload_size: 1350,
+            datagrams: false,
+            datagram_queue_len: 1000,
+            congestion_control: CongestionControl::default(),
+            hystart: true,
+            pacing: true,
+            keepalive_ms: 0,
+        }
+    }
+}
+
+
+/// Transport parameters that a profile or destination changes from the
+/// defaults.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportOverrides {
+    pub idle_timeout_ms: Option<u64>,
+    pub max_data: Option<u64>,
+    pub max_stream_data_bidi_local: Option<u64>,
+    pub max_stream_data_bidi_remote: Option<u64>,
+    pub max_stream_data_uni: Option<u64>,
+    pub max_streams_bidi: Option<u64>,
+    pub max_streams_uni: Option<u64>,
+    pub max_udp_payload_size: Option<usize>,
+    pub datagrams: Option<bool>,
+    pub datagram_queue_len: Option<usize>,
+    pub congestion_control: Option<CongestionControl>,
+    pub hystart: Option<bool>,
+    pub pacing: Option<bool>,
+    pub keepalive_ms: Option<u64>,
+}
+
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Verify server certificates, true if not set.
+    pub verify: Option<bool>,
+    pub ca_file: Option<String>,
+    pub ca_dir: Option<String>,
+    /// Client identities by name, in `[tls.identities.<name>]` tables.
+    pub identities: HashMap<String, IdentityConfig>,
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdentityConfig {
+    pub cert_chain: String,
+    pub key: String,
+    /// Names or IDs of local users that may use the identity, "*" for all.
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub name: String,
+    /// Server names or IP addresses, `*.example.com` for names under a domain.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Networks of server addresses, in CIDR notation.
+    #[serde(default)]
+    pub networks: Vec<String>,
+    /// Application protocols.
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    #[serde(default)]
+    pub transport: TransportOverrides,
+}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DestinationConfig {
+    /// Server name or IP address of the destination.
+    pub host: String,
+    /// Accepted certificates, `cert-sha256:<hex>` or `spki-sha256:<hex>`.
+    #[serde(default)]
+    pub pins: Vec<String>,
+    /// Client identity used when client does not request one.
+    pub identity: Option<String>,
+    #[serde(default)]
+    pub transport: TransportOverrides,
+}
+
+
+/// Settings of the manager, from configuration file and command line.
+pub struct Settings {
+    pub socket: PathBuf,
+    pub log_level: Option<String>,
+    pub session_cache_dir: Option<PathBuf>,
+    pub policy: PoolPolicy,
+    pub spill: SpillPolicy,
+    pub tls: TlsSettings,
+    pub transport: TransportParams,
+    pub profiles: Vec<Profile>,
+    /// Transport parameters of destinations that override defaults and
+    /// profiles. Key is server name or IP address.
+    pub destinations: HashMap<String, TransportOverrides>,
+}
+
+impl Settings {
+    /// Read configuration file given in `args`, and apply command line options
+    /// on top of it. Returns description of the problem if settings are
+    /// invalid.
+    pub fn load(args: Args) -> Result<Settings, String> {
+        let mut file = match &args.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+                toml::from_str(&text)
+                    .map_err(|e| format!("Invalid configuration {}: {}", path.display(), e))?
+            },
+            None => ConfigFile::default(),
+        };
+        if args.tls_no_verify {
+            file.tls.verify = Some(false);
+        }
+        if args.tls_ca_file.is_some() {
+            file.tls.ca_file = args.tls_ca_file;
+        }
+        if args.tls_ca_dir.is_some() {
+            file.tls.ca_dir = args.tls_ca_dir;
+        }
+        file.transport = file.transport.with(&TransportOverrides {
+            idle_timeout_ms: args.idle_timeout,
+            max_data: args.max_data,
+            max_stream_data_bidi_local: args.max_stream_data,
+            max_stream_data_bidi_remote: args.max_stream_data,
+            max_stream_data_uni: args.max_stream_data,
+            max_streams_bidi: args.max_streams_bidi,
+            max_streams_uni: args.max_streams_uni,
+            max_udp_payload_size: args.max_udp_payload,
+            ..Default::default()
+        });
+        Settings::from_file(
+            file,
+            args.socket,
+            args.log_level,
+            args.session_cache_dir,
+        )
+    }
+
+
+    fn from_file(
+        file: ConfigFile,
+        socket: Option<PathBuf>,
+        log_level: Option<String>,
+        session_cache_dir: Option<PathBuf>,
+    ) -> Result<Settings, String> {
+        let policy = match &file.pool.policy {
+            Some(name) => PoolPolicy::from_name(name)
+                .ok_or(format!("Unknown pool policy '{}'", name))?,
+            None => PoolPolicy::default(),
+        };
+        if file.pool.min_connections == Some(0) {
+            return Err("min_connections must not be 0".to_string());
+        }
+        file.transport.validate()?;
+        let mut profiles = Vec::new();
+        for config in &file.profiles {
+            file.transport.with(&config.transport).validate()
+                .map_err(|e| format!("Profile '{}': {}", config.name, e))?;
+            profiles.push(Profile::from_config(config)?);
+        }
+        let mut destinations = HashMap::new();
+        for dest in &file.destinations {
+            file.transport.with(&dest.transport).validate()
+                .map_err(|e| format!("Destination '{}': {}", dest.host, e))?;
+            let host = dest.host.trim_end_matches('.').to_ascii_lowercase();
+            if destinations.insert(host, dest.transport.clone()).is_some() {
+                return Err(format!("Destination '{}' is given twice", dest.host));
+            }
+        }
+        Ok(Settings {
+            socket: socket.or(file.socket).unwrap_or_else(|| QCM_CONTROL_SOCKET.into()),
+            log_level: log_level.or(file.log_level),
+            session_cache_dir: session_cache_dir.or(file.session_cache_dir),
+            policy,
+            spill: SpillPolicy {
+                queue_depth: file.pool.spill_queue_depth,
+                max_wait: file.pool.spill_wait_ms.map(Duration::from_millis),
+                min_connections: file.pool.min_connections.unwrap_or(1),
+            },
+            tls: TlsSettings::from_config(&file.tls, &file.destinations)?,
+            transport: file.transport,
+            profiles,
+            destinations,
+        })
+    }
+
+
+    /// Returns transport parameters for connection to destination `key`: the
+    /// defaults, changed by the first matching profile, and then by settings of
+    /// the destination.
+    pub fn transport_for(&self, key: &ConnectionKey) -> TransportParams {
+        let mut params = self.transport;
+        if let Some(profile) = self.profiles.iter().find(|p| p.matches(key)) {
+            debug!("Using profile '{}' for {}", profile.name, key.host());
+            params = params.with(&profile.transport);
+        }
+        if let Some(overrides) = self.destinations.get(&key.host()) {
+            params = params.with(overrides);
+        }
+        params
+    }
+}
+
+
+impl TransportParams {
+    /// Returns parameters with values that are set in `overrides` replaced.
+    fn with(&self, overrides: &TransportOverrides) -> TransportParams {
+        TransportParams {
+            idle_timeout_ms: overrides.idle_timeout_ms.unwrap_or(self.idle_timeout_ms),
+            max_data: overrides.max_data.unwrap_or(self.max_data),
+            max_stream_data_bidi_local: overrides.max_stream_data_bidi_local
+                .unwrap_or(self.max_stream_data_bidi_local),
+            max_stream_data_bidi_remote: overrides.max_stream_data_bidi_remote
+                .unwrap_or(self.max_stream_data_bidi_remote),
+            max_stream_data_uni: overrides.max_stream_data_uni
+                .unwrap_or(self.max_stream_data_uni),
+            max_streams_bidi: overrides.max_streams_bidi.unwrap_or(self.max_streams_bidi),
+            max_streams_uni: overrides.max_streams_uni.unwrap_or(self.max_streams_uni),
+            max_udp_payload_size: overrides.max_udp_payload_size
+                .unwrap_or(self.max_udp_payload_size),
+            datagrams: overrides.datagrams.unwrap_or(self.datagrams),
+            datagram_queue_len: overrides.datagram_queue_len.unwrap_or(self.datagram_queue_len),
+            congestion_control: overrides.congestion_control.unwrap_or(self.congestion_control),
+            hystart: overrides.hystart.unwrap_or(self.hystart),
+            pacing: overrides.pacing.unwrap_or(self.pacing),
+            keepalive_ms: overrides.keepalive_ms.unwrap_or(self.keepalive_ms),
+        }
+    }
+
+
+    fn validate(&self) -> Result<(), String> {
+        if !(MIN_UDP_PAYLOAD_SIZE..=MAX_UDP_PAYLOAD_SIZE).contains(&self.max_udp_payload_size) {
+            return Err(format!(
+                "max_udp_payload_size must be between {} and {}",
+                MIN_UDP_PAYLOAD_SIZE, MAX_UDP_PAYLOAD_SIZE
+            ));
+        }
+        if self.max_data == 0 {
+            return Err("max_data must not be 0".to_string());
+        }
+        if self.datagram_queue_len == 0 {
+            return Err("datagram_queue_len must not be 0".to_string());
+        }
+        if self.keepalive_ms > 0 && self.idle_timeout_ms > 0
+            && self.keepalive_ms >= self.idle_timeout_ms {
+            return Err("keepalive_ms must be shorter than idle_timeout_ms".to_string());
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(args: &[&str]) -> Result<Settings, String> {
+        let args = Args::try_parse_from([&["quic-cm-manager"], args].concat())
+            .map_err(|e| e.to_string())?;
+        Settings::load(args)
+    }
+
+
+    #[test]
+    fn test_validate() {
+        let defaults = TransportParams::default();
+        assert!(defaults.validate().is_ok());
+        let valid = [
+            TransportParams { max_udp_payload_size: MIN_UDP_PAYLOAD_SIZE, ..defaults },
+            TransportParams { max_udp_payload_size: MAX_UDP_PAYLOAD_SIZE, ..defaults },
+            TransportParams { keepalive_ms: 49999, idle_timeout_ms: 50000, ..defaults },
+            // Keepalive is allowed without idle timeout
+            TransportParams { keepalive_ms: 100000, idle_timeout_ms: 0, ..defaults },
+        ];
+        for params in valid {
+            assert_eq!(params.validate(), Ok(()), "{:?}", params);
+        }
+        let invalid = [
+            (TransportParams { max_udp_payload_size: MIN_UDP_PAYLOAD_SIZE - 1, ..defaults },
+             "max_udp_payload_size"),
+            (TransportParams { max_udp_payload_size: MAX_UDP_PAYLOAD_SIZE + 1, ..defaults },
+             "max_udp_payload_size"),
+            (TransportParams { max_data: 0, ..defaults }, "max_data"),
+            (TransportParams { datagram_queue_len: 0, ..defaults }, "datagram_queue_len"),
+            (TransportParams { keepalive_ms: 50000, idle_timeout_ms: 50000, ..defaults },
+             "keepalive_ms"),
+            (TransportParams { keepalive_ms: 60000, idle_timeout_ms: 50000, ..defaults },
+             "keepalive_ms"),
+        ];
+        for (params, field) in invalid {
+            assert!(params.validate().is_err_and(|e| e.starts_with(field)), "{:?}", params);
+        }
+    }
+
+
+    #[test]
+    fn test_command_line_transport() {
+        let settings = load(&[
+            "--idle-timeout", "1000",
+            "--max-data", "2000",
+            "--max-stream-data", "3000",
+            "--max-streams-bidi", "4",
+            "--max-streams-uni", "5",
+            "--max-udp-payload", "1400",
+        ]).unwrap();
+        let params = settings.transport;
+        assert_eq!(params.idle_timeout_ms, 1000);
+        assert_eq!(params.max_data, 2000);
+        assert_eq!(params.max_stream_data_bidi_local, 3000);
+        assert_eq!(params.max_stream_data_bidi_remote, 3000);
+        assert_eq!(params.max_stream_data_uni, 3000);
+        assert_eq!(params.max_streams_bidi, 4);
+        assert_eq!(params.max_streams_uni, 5);
+        assert_eq!(params.max_udp_payload_size, 1400);
+        assert_eq!(params.keepalive_ms, TransportParams::default().keepalive_ms);
+    }
+
+
+    #[test]
+    fn test_profile_precedence() {
+        let file: ConfigFile = toml::from_str(r#"
+            [transport]
+            max_data = 1000
+            max_streams_bidi = 10
+
+            [[profile]]
+            name = "narrow"
+            hosts = ["a.example.com"]
+            [profile.transport]
+            max_data = 2000
+
+            [[profile]]
+            name = "wide"
+            hosts = ["*.example.com"]
+            networks = ["0.0.0.0/0"]
+            [profile.transport]
+            max_data = 3000
+            max_streams_bidi = 30
+
+            [[destination]]
+            host = "B.example.com."
+            [destination.transport]
+            max_streams_bidi = 40
+        "#).unwrap();
+        let settings = Settings::from_file(file, None, None, None).unwrap();
+        let transport = |name: &str| settings.transport_for(&ConnectionKey {
+            peer: "127.0.0.1:443".parse().unwrap(),
+            app_proto: "h3".to_string(),
+            server_name: Some(name.to_string()),
+            identity: None,
+        });
+        // First matching profile is used, without the later ones
+        let params = transport("a.example.com");
+        assert_eq!((params.max_data, params.max_streams_bidi), (2000, 10));
+        let params = transport("c.example.com");
+        assert_eq!((params.max_data, params.max_streams_bidi), (3000, 30));
+        // Destination overrides profile
+        let params = transport("b.example.com");
+        assert_eq!((params.max_data, params.max_streams_bidi), (3000, 40));
+        let params = transport("example.org");
+        assert_eq!((params.max_data, params.max_streams_bidi), (1000, 10));
+    }
+
+
+    #[test]
+    fn test_command_line_validation() {
+        assert!(load(&[]).is_ok());
+        assert!(load(&["--max-udp-payload", "1100"])
+            .is_err_and(|e| e.starts_with("max_udp_payload_size")));
+        assert!(load(&["--max-data", "0"]).is_err_and(|e| e.starts_with("max_data")));
+        assert!(load(&["--max-data", "-1"]).is_err());
+    }
+}
@@ -1,8 +1,9 @@
 use std::{
-    collections::HashMap,
-    net::ToSocketAddrs, os::unix::net::UnixStream,
+    collections::{HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    os::unix::net::UnixStream,
     os::fd::AsRawFd,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use mio::{
     event::Event,
@@ -14,13 +15,42 @@ use mio::{
 };
 use ring::rand::*;
 use quiche::Config;
+use quic_cm::{
+    Error,
+    common::{
+        CLIENT_GONE_ERROR_CODE,
+        MAX_DATA_LEN,
+        get_varint,
+        is_bidi,
+        is_server_initiated,
+        put_varint,
+        codec::{ConnectionStats, FrameDecoder},
+    },
+};
 
 use crate::{
-    client::Client,
+    client::{Client, ControlMsg},
+    config::{CongestionControl, MAX_UDP_PAYLOAD_SIZE, Settings, TransportParams},
+    destination::ConnectionKey,
     mio_tokens::TokenManager,
+    pool::SpillPolicy,
+    tls::{Pin, TlsSettings, check_ip_address, check_pins, crypto_error_code},
 };
 
-const MAX_DATAGRAM_SIZE: usize = 1350;
+/// Default urgency of a stream in quiche.
+const DEFAULT_URGENCY: u8 = 127;
+
+/// Default max_ack_delay of QUIC, the longest time that the server delays
+/// acknowledgments.
+const MAX_ACK_DELAY: Duration = Duration::from_millis(25);
+
+/// Client request to open a stream, waiting to be served.
+struct PendingOpen {
+    token: Token,
+    bidi: bool,
+    since: Instant,
+}
+
 
 pub enum State {
     Connecting,
@@ -28,47 +58,98 @@ pub enum State {
     Closed,
 }
 
-/// QUIC connection to a server destination. There are separate QUIC-CM clients for
-/// each stream opened with the server.
+/// QUIC connection to a server destination. Each QUIC-CM client is a session that
+/// can open several streams in the connection.
 pub struct Connection {
     socket: UdpSocket,
-    app_proto: String,
+    key: ConnectionKey,
     token: Token,
     qconn: quiche::Connection,
     state: State,
-    received_data: HashMap<u64, Vec<u8>>,
-    clients: HashMap<u64, Client>,  // Key is QUIC stream ID
-    next_stream_id: u64,
+    /// Streams on which client has been told that peer stopped reading.
+    stop_notified: HashSet<u64>,
+    clients: HashMap<Token, Client>,
+    /// Client that owns each open stream. Key is QUIC stream ID.
+    streams: HashMap<u64, Token>,
+    /// Streams that clients have asked to open, but that cannot be opened yet,
+    /// because connection is not established, or the peer's stream limit has
+    /// been reached.
+    pending_opens: VecDeque<PendingOpen>,
+    /// Clients that accept bidirectional streams opened by the server, in the
+    /// order they get the next stream.
+    bidi_acceptors: VecDeque<Token>,
+    /// Clients that accept unidirectional streams opened by the server.
+    uni_acceptors: VecDeque<Token>,
+    /// Next client-initiated bidirectional stream ID (0, 4, 8, ...). Streams
+    /// opened by the server have odd IDs (1, 5, 9, ... and 3, 7, 11, ...), and
+    /// are routed to acceptors as they arrive.
+    next_bidi_id: u64,
+    /// Next client-initiated unidirectional stream ID (2, 6, 10, ...).
+    next_uni_id: u64,
+    /// Client that owns each datagram flow. Key is flow ID.
+    flows: HashMap<u64, Token>,
+    next_flow_id: u64,
+    /// Datagrams are offered to the server.
+    datagrams: bool,
+    /// Certificates accepted for the destination. Empty if any certificate
+    /// that passes verification is accepted.
+    pins: Vec<Pin>,
+    /// Address that the server certificate must be issued for, when TLS does
+    /// not verify a server name.
+    verify_address: Option<IpAddr>,
+    /// TLS session that was last given to the session cache.
+    session: Option<Vec<u8>>,
+    /// Interval of keepalive packets.
+    keepalive: Option<Duration>,
+    congestion_control: CongestionControl,
+    /// When a packet was last sent.
+    last_send: Instant,
+    /// Number of stream bytes sent in 0-RTT.
+    early_bytes: u64,
+    /// When clients connected in 0-RTT are told that their early data was
+    /// accepted, unless it is retransmitted before that.
+    early_data_deadline: Option<Instant>,
+    /// Clients have been told why the connection was closed.
+    close_reported: bool,
 }
 
 impl Connection {
 
     pub fn new(
-        address: &str,
-        app_proto: &str,
+        key: &ConnectionKey,
+        settings: &Settings,
+        congestion_control: Option<CongestionControl>,
+        session: Option<&[u8]>,
         tokenmanager: &mut TokenManager,
         poll: &mut Poll,
-    ) -> Result<Connection, String> {
-        let addr = match Self::resolve_address(address) {
-            Ok(addr) => addr,
-            Err(e) => { return Err(e); },
-        };
+    ) -> Result<Connection, Error> {
+        let addr = key.peer;
         let bind_addr = match addr {
             std::net::SocketAddr::V4(_) => "0.0.0.0:0",
             std::net::SocketAddr::V6(_) => "[::]:0",
         };
-        let mut socket = UdpSocket::bind(bind_addr.parse().unwrap()).unwrap();
-        let local_addr = socket.local_addr().unwrap();
+        let mut socket = UdpSocket::bind(bind_addr.parse().unwrap())?;
+        let local_addr = socket.local_addr()?;
 
         let mut scid = [0; quiche::MAX_CONN_ID_LEN];
         SystemRandom::new().fill(&mut scid[..]).unwrap();
         let scid = quiche::ConnectionId::from_ref(&scid);
 
-        let mut config = set_quic_config(app_proto);
+        let mut transport = settings.transport_for(key);
+        if let Some(cc) = congestion_control {
+            transport.congestion_control = cc;
+        }
+        debug!("Using {} congestion control for {}", transport.congestion_control.name(), key.host());
+        let mut config = set_quic_config(key, &settings.tls, &transport)?;
 
         let mut conn =
-            quiche::connect(None, &scid, local_addr, addr, &mut config)
-                .unwrap();
+            quiche::connect(key.server_name.as_deref(), &scid, local_addr, addr, &mut config)
+                .map_err(|e| Error::Handshake(format!("{:?}", e)))?;
+        if let Some(session) = session {
+            if let Err(e) = conn.set_session(session) {
+                warn!("Could not resume TLS session: {:?}", e);
+            }
+        }
 
         debug!(
             "connecting to {:} from {:} with scid {}",
@@ -77,18 +158,19 @@ impl Connection {
             hex_dump(&scid)
         );
 
-        let mut out = [0; MAX_DATAGRAM_SIZE];
-        let (write, send_info) = conn.send(&mut out).expect("initial send failed");
+        let mut out = [0; MAX_UDP_PAYLOAD_SIZE];
+        let (write, send_info) = conn.send(&mut out)
+            .map_err(|e| Error::Handshake(format!("Initial send failed: {:?}", e)))?;
 
-        while let Err(e) = socket.send_to(&out[..write], send_info.to) {
-            if e.kind() == std::io::ErrorKind::WouldBlock {
-                println!("send() would block");
-                continue;
-            }
-
-            error!("send() failed: {:?}", e);
+        match socket.send_to(&out[..write], send_info.to) {
+            Ok(_) => debug!("connecting, written {} bytes", write),
+            // The packet is retransmitted like a lost one.
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => debug!("send() would block"),
+            Err(e) => {
+                error!("Could not send to {}: {}", addr, e);
+                return Err(Error::Io(e));
+            },
         }
-        debug!("connecting, written {} bytes", write);
 
         let token = tokenmanager.allocate_token();
         poll.registry()
@@ -96,14 +178,35 @@ impl Connection {
             .unwrap();
 
         Ok(Connection {
-            socket: socket,
-            app_proto: app_proto.to_string(),
-            token: token,
+            socket,
+            key: key.clone(),
+            token,
             qconn: conn,
             state: State::Connecting,
-            received_data: HashMap::new(),
+            stop_notified: HashSet::new(),
             clients: HashMap::new(),
-            next_stream_id: 4,
+            streams: HashMap::new(),
+            pending_opens: VecDeque::new(),
+            bidi_acceptors: VecDeque::new(),
+            uni_acceptors: VecDeque::new(),
+            next_bidi_id: 0,
+            next_uni_id: 2,
+            flows: HashMap::new(),
+            next_flow_id: 0,
+            datagrams: transport.datagrams,
+            pins: settings.tls.pins_for(&key.host()),
+            verify_address: settings
+                .tls
+                .checks_address(key.server_name.as_deref(), &key.host())
+                .then(|| key.peer.ip()),
+            session: None,
+            keepalive: (transport.keepalive_ms > 0)
+                .then(|| Duration::from_millis(transport.keepalive_ms)),
+            last_send: Instant::now(),
+            congestion_control: transport.congestion_control,
+            early_bytes: 0,
+            early_data_deadline: None,
+            close_reported: false,
         })
     }
 
@@ -149,69 +252,78 @@ impl Connection {
             return;
         }
 
-        if self.qconn.peer_error().is_some() {
-            error!("Error occurred when establishing connection");
-            for client in self.clients.values_mut() {
-                client.send_error("Error occurred when establishing connection");
-            }
+        if self.qconn.peer_error().is_some() && !self.close_reported {
+            let err = self.close_error();
+            error!("Peer closed connection: {}", err);
+            self.report_close(&err);
         }
 
         if self.qconn.is_established() {
             if let State::Connecting = self.state {
+                if let Err(e) = self.check_certificate() {
+                    error!("Rejecting server certificate: {}", e);
+                    self.report_close(&e);
+                    let _ = self.qconn.close(false, crypto_error_code(), b"Certificate rejected");
+                    return;
+                }
                 self.state = State::Established;
+                let resumed = self.qconn.is_resumed();
+                debug!("Connection established, resumed: {}", resumed);
                 for client in self.clients.values_mut() {
-                    client.send_ok();
+                    if !client.is_in_early_data() {
+                        client.send_ok(self.congestion_control.name());
+                    }
                 }
+                self.start_early_data_check(resumed);
             }
+            self.check_early_data();
+            // Peer may have raised its stream limit.
+            self.open_pending();
             self.handle_established();
+            self.check_stopped();
+            self.recv_datagrams();
         }
     }
 
 
     /// Process MIO events. If event is None, timeout has occurred.
-    pub fn process_events(&mut self, event: Option<&Event>, tokenmanager: &mut TokenManager) -> Result<(), String> {
-        if event.is_some() {
-            if event.unwrap().token() == self.token {
+    pub fn process_events(&mut self, event: Option<&Event>, tokenmanager: &mut TokenManager) -> Result<(), Error> {
+        if let Some(event) = event {
+            if event.token() == self.token {
                 // TODO: error handling
                 self.process_datagram();
             }
 
-            let mut leaving: Vec<u64> = Vec::new();
-            let mut writing: Vec<u64> = Vec::new();
-            for (stream_id, client) in self.clients.iter_mut() {
-                if event.unwrap().token() == client.get_token() {
-                    match client.process_control_msg() {
-                        Ok(n) => {
-                            if n == 0 {
-                                info!("Client leaving");
-                                // TODO: close stream
-                                client.cleanup(tokenmanager);
-                                leaving.push(*stream_id);
-                            } else {
-                                writing.push(*stream_id);
-                                // TODO: send OK response
-                                client.send_ok();
-                            }
-                        },
-                        Err(e) => return Err(format!("process client: {}", e)),
+            let token = event.token();
+            if let Some(client) = self.clients.get_mut(&token) {
+                if event.is_writable() {
+                    client.flush();
+                    if !client.is_congested() {
+                        for stream_id in client.stream_ids() {
+                            self.recv_stream(stream_id);
+                        }
                     }
                 }
-            }
-            for c in writing {
-                self.send(c).unwrap();  // TODO: handle errors
-            }
-            for index in leaving {
-                self.clients.remove(&index);
+                if event.is_readable() || event.is_read_closed() {
+                    self.serve_client(token, tokenmanager);
+                }
             }
         } else {
             self.qconn.on_timeout();
+            self.check_early_data();
+            if self.keepalive_deadline().is_some_and(|t| t <= Instant::now()) {
+                debug!("Sending keepalive");
+                if let Err(e) = self.qconn.send_ack_eliciting() {
+                    debug!("Could not send keepalive: {:?}", e);
+                }
+            }
         }
+        self.resume_blocked(tokenmanager);
 
-        if self.qconn.is_closed() {
+        if self.qconn.is_closed() && !self.close_reported {
             debug!("Connection is closed");
-            for c in self.clients.values_mut() {
-                c.send_error("Connection is closed");
-            }
+            let err = self.close_error();
+            self.report_close(&err);
         }
 
         self.send_data();
@@ -219,53 +331,401 @@ impl Connection {
     }
 
 
-    pub fn add_client(&mut self, socket: UnixStream, app_proto: &str, poll: &mut Poll, token: Token) {
-        // check that app_proto matches with earlier made connectiom
-        if app_proto.ne(&self.app_proto) {
-                let mut mutsock = socket;
-                Client::send_socket_error(
-                    &mut mutsock,
-                    format!("Application proto from new client does not match connection.").as_str()
-                );
-                return;
+    pub fn add_client(
+        &mut self,
+        socket: UnixStream,
+        decoder: FrameDecoder,
+        poll: &mut Poll,
+        token: Token,
+        early_data: bool,
+        tokenmanager: &mut TokenManager,
+    ) {
+        if let Err(e) = socket.set_nonblocking(true) {
+            let mut mutsock = socket;
+            Client::send_socket_error(&mut mutsock, &Error::Io(e));
+            tokenmanager.free_token(token);
+            return;
         }
 
         poll.registry()
             .register(&mut SourceFd(&socket.as_raw_fd()),
-                token, Interest::READABLE)
+                token, Interest::READABLE | Interest::WRITABLE)
             .unwrap();
 
-        let stream_id: u64 = self.next_stream_id;
-        debug!("add_client, stream ID: {}", stream_id);
-        self.next_stream_id += 4;
-        let mut client = Client::new(socket, token);
-        if let State::Established = self.state {
-            client.send_ok();
+        debug!("add_client, token: {:?}", token);
+        let mut client = Client::new(socket, token, decoder);
+        match self.state {
+            State::Established => client.send_ok(self.congestion_control.name()),
+            State::Connecting if early_data && self.qconn.is_in_early_data() => {
+                debug!("Client {:?} connected in 0-RTT", token);
+                client.send_early_ok(self.congestion_control.name());
+            },
+            _ => (),
         }
-        self.clients.insert(
-            stream_id,
-            client
-        );
+        self.clients.insert(token, client);
+        // Client may have sent frames right after CONNECT.
+        self.serve_client(token, tokenmanager);
+    }
+
+
+    /// Open new stream for client, or queue the request until the stream can
+    /// be opened.
+    fn open_stream(&mut self, token: Token, bidi: bool) {
+        self.pending_opens.push_back(PendingOpen { token, bidi, since: Instant::now() });
+        self.open_pending();
     }
 
 
-    pub fn send(&mut self, stream_id: u64) -> Result<usize, String> {
-        let client = self.clients.get_mut(&stream_id).unwrap();
-        let (n, buf) = client.fetch_databuf();
-        let written = match self.qconn.stream_send(stream_id, &buf[..n], false) {
-            Ok(n) => n,
-            Err(quiche::Error::Done) => 0,
+    /// Open queued streams in the order they were requested, as far as the
+    /// peer's MAX_STREAMS limit allows. Stream IDs are assigned by manager,
+    /// because clients of the connection share the stream ID space. Before the
+    /// handshake completes, only streams of clients connected in 0-RTT are
+    /// opened.
+    fn open_pending(&mut self) {
+        let early = match self.state {
+            State::Connecting if self.qconn.is_in_early_data() => true,
+            State::Connecting => return,
+            _ => false,
+        };
+        let mut blocked = VecDeque::new();
+        while let Some(open) = self.pending_opens.pop_front() {
+            if early && !self.clients.get(&open.token).is_some_and(Client::is_in_early_data) {
+                blocked.push_back(open);
+                continue;
+            }
+            let left = if open.bidi {
+                self.qconn.peer_streams_left_bidi()
+            } else {
+                self.qconn.peer_streams_left_uni()
+            };
+            if left == 0 {
+                blocked.push_back(open);
+                continue;
+            }
+            let client = match self.clients.get_mut(&open.token) {
+                Some(client) => client,
+                None => continue,
+            };
+            let next_id = if open.bidi { &mut self.next_bidi_id } else { &mut self.next_uni_id };
+            let stream_id = *next_id;
+            // Create the stream in quiche now, so that it counts against the
+            // peer's limit before any data is sent on it.
+            match self.qconn.stream_priority(stream_id, DEFAULT_URGENCY, true) {
+                Ok(()) => (),
+                Err(quiche::Error::StreamLimit) => {
+                    blocked.push_back(open);
+                    continue;
+                },
+                Err(e) => {
+                    // Stream ID is not used again, quiche may hold state of it.
+                    *next_id += 4;
+                    error!("Could not create stream {}: {:?}", stream_id, e);
+                    client.send_error(&Error::Io(std::io::Error::other(
+                        format!("Could not open stream: {:?}", e)
+                    )));
+                    continue;
+                },
+            }
+            *next_id += 4;
+            debug!("Opening stream {} for {:?}", stream_id, open.token);
+            client.open_stream(stream_id, open.bidi);
+            self.streams.insert(stream_id, open.token);
+        }
+        if !blocked.is_empty() {
+            debug!("Peer stream limit reached, {} streams waiting", blocked.len());
+        }
+        self.pending_opens = blocked;
+    }
+
+
+    /// Returns true if so many streams are waiting for the peer's stream
+    /// limit, or they have waited so long, that new clients should rather get
+    /// a new connection. Streams wait for the handshake too, so the number of
+    /// waiting streams counts also while connecting, but the time they have
+    /// waited only after that.
+    pub fn is_saturated(&self, policy: &SpillPolicy) -> bool {
+        let depth = policy.queue_depth.is_some_and(|d| self.pending_opens.len() >= d);
+        let waited = match (&self.state, policy.max_wait, self.pending_opens.front()) {
+            (State::Established, Some(max_wait), Some(open)) => open.since.elapsed() >= max_wait,
+            _ => false,
+        };
+        depth || waited
+    }
+
+
+    /// Returns true if new clients can be added to the connection. Connection
+    /// that is draining or closing, or that the peer has closed, only serves
+    /// its existing clients until it goes away.
+    pub fn is_usable(&self) -> bool {
+        !self.qconn.is_closed()
+            && !self.qconn.is_draining()
+            && self.qconn.peer_error().is_none()
+            && self.qconn.local_error().is_none()
+    }
+
+
+    /// Returns number of streams that are open or waiting to be opened.
+    pub fn num_streams(&self) -> usize {
+        self.streams.len() + self.pending_opens.len()
+    }
+
+
+    /// Read control messages from client and pass its data to the QUIC
+    /// streams, as much as the streams accept. Client that has left is removed
+    /// once its data is sent.
+    fn serve_client(&mut self, token: Token, tokenmanager: &mut TokenManager) {
+        let client = match self.clients.get_mut(&token) {
+            Some(client) => client,
+            None => return,
+        };
+        let msgs = match client.process_control_msg() {
+            Ok(msgs) => msgs,
             Err(e) => {
-                return Err(format!("{} stream send failed {:?}", self.qconn.trace_id(), e));
+                error!("Dropping client {:?}: {}", token, e);
+                client.send_error(&e);
+                self.remove_client(token, tokenmanager);
+                return;
             },
         };
-        debug!("send wrote {} bytes to stream {}", written, stream_id);
-        Ok(written)
+        for msg in msgs {
+            match msg {
+                ControlMsg::Closed => info!("Client leaving"),
+                ControlMsg::Open { bidi } => self.open_stream(token, bidi),
+                ControlMsg::Accept { bidi } => {
+                    let acceptors = self.acceptors(bidi);
+                    if !acceptors.contains(&token) {
+                        acceptors.push_back(token);
+                    }
+                    // Deliver streams that the server opened before.
+                    if let State::Established = self.state {
+                        self.handle_established();
+                    }
+                },
+                ControlMsg::Reset { stream_id, code } => {
+                    self.shutdown_stream(stream_id, quiche::Shutdown::Write, code);
+                },
+                ControlMsg::StopSending { stream_id, code } => {
+                    self.shutdown_stream(stream_id, quiche::Shutdown::Read, code);
+                },
+                ControlMsg::Credit { stream_id } => {
+                    if let State::Established = self.state {
+                        self.recv_stream(stream_id);
+                    }
+                },
+                ControlMsg::DatagramOpen => self.open_flow(token),
+                ControlMsg::Datagram(data) => self.send_datagram(token, &data),
+                ControlMsg::Stats => {
+                    let stats = self.stats();
+                    self.clients.get_mut(&token).unwrap().send_stats(stats);
+                },
+            }
+        }
+        for stream_id in self.clients.get(&token).unwrap().pending_streams() {
+            self.send(token, stream_id);
+        }
+        self.finish_client(token, tokenmanager);
+    }
+
+
+    /// Forget streams of client that are closed in both directions, and remove
+    /// client that has left, once data of its finished streams is sent.
+    fn finish_client(&mut self, token: Token, tokenmanager: &mut TokenManager) {
+        let client = match self.clients.get_mut(&token) {
+            Some(client) => client,
+            None => return,
+        };
+        for stream_id in client.stream_ids() {
+            if client.is_stream_done(stream_id) {
+                debug!("Stream {} is done", stream_id);
+                client.remove_stream(stream_id);
+                self.streams.remove(&stream_id);
+                self.stop_notified.remove(&stream_id);
+            }
+        }
+        // Keep the client until rest of its data fits in the streams.
+        if client.is_closed() && !client.has_pending_fin() {
+            self.remove_client(token, tokenmanager);
+        }
+    }
+
+
+    /// Pass pending data from client to QUIC stream, as much as the stream
+    /// accepts.
+    fn send(&mut self, token: Token, stream_id: u64) {
+        let client = self.clients.get_mut(&token).unwrap();
+        let (buf, fin) = client.pending_data(stream_id);
+        let len = buf.len();
+        match self.qconn.stream_send(stream_id, buf, fin) {
+            Ok(n) => {
+                debug!("send wrote {} bytes to stream {}", n, stream_id);
+                if self.qconn.is_in_early_data() {
+                    self.early_bytes += n as u64;
+                }
+                client.consume(stream_id, n, fin && n == len);
+            },
+            Err(quiche::Error::Done) => {
+                debug!("Stream {} is blocked", stream_id);
+            },
+            Err(quiche::Error::StreamStopped(code)) => {
+                client.discard_pending(stream_id);
+                self.notify_stopped(stream_id, code);
+            },
+            Err(e) => {
+                let err = Error::Io(std::io::Error::other(
+                    format!("{} stream send failed {:?}", self.qconn.trace_id(), e)
+                ));
+                error!("Sending to stream {} failed: {}", stream_id, err);
+                client.discard_pending(stream_id);
+                client.send_error(&err);
+            },
+        };
+    }
+
+
+    /// Continue with streams whose data did not fit in the QUIC stream earlier,
+    /// if the stream has become writable.
+    fn resume_blocked(&mut self, tokenmanager: &mut TokenManager) {
+        let tokens: Vec<Token> = self.clients.keys().copied().collect();
+        for token in tokens {
+            let blocked = self.clients.get(&token).unwrap().pending_streams();
+            if blocked.is_empty() {
+                continue;
+            }
+            for stream_id in blocked {
+                // Errors, such as stopped stream, are handled when sending.
+                if self.qconn.stream_writable(stream_id, 1).unwrap_or(true) {
+                    self.send(token, stream_id);
+                }
+            }
+            self.finish_client(token, tokenmanager);
+        }
+    }
+
+
+    /// Remove client that has left or failed, and close its streams.
+    fn remove_client(&mut self, token: Token, tokenmanager: &mut TokenManager) {
+        let client = match self.clients.remove(&token) {
+            Some(client) => client,
+            None => return,
+        };
+        for stream_id in client.stream_ids() {
+            self.close_stream(stream_id, client.has_finished(stream_id));
+            self.streams.remove(&stream_id);
+        }
+        self.pending_opens.retain(|open| open.token != token);
+        self.bidi_acceptors.retain(|t| *t != token);
+        self.uni_acceptors.retain(|t| *t != token);
+        if let Some(flow_id) = client.flow() {
+            self.flows.remove(&flow_id);
+        }
+        client.cleanup(tokenmanager);
+    }
+
+
+    pub fn stats(&self) -> ConnectionStats {
+        let stats = self.qconn.stats();
+        let mut result = ConnectionStats {
+            sent: stats.sent as u64,
+            recv: stats.recv as u64,
+            lost: stats.lost as u64,
+            sent_bytes: stats.sent_bytes,
+            recv_bytes: stats.recv_bytes,
+            congestion_control: self.congestion_control.name().to_string(),
+            ..Default::default()
+        };
+        if let Some(path) = self.qconn.path_stats().find(|p| p.active) {
+            result.rtt = path.rtt;
+            result.cwnd = path.cwnd as u64;
+            result.delivery_rate = path.delivery_rate;
+        }
+        result
+    }
+
+
+    pub fn congestion_control(&self) -> CongestionControl {
+        self.congestion_control
+    }
+
+
+    pub fn key(&self) -> &ConnectionKey {
+        &self.key
+    }
+
+
+    /// Returns TLS session if it has changed since last call, for example
+    /// after the server has sent a new session ticket.
+    pub fn new_session(&mut self) -> Option<Vec<u8>> {
+        let session = self.qconn.session()?;
+        if self.session.as_deref() == Some(session) {
+            return None;
+        }
+        self.session = Some(session.to_vec());
+        self.session.clone()
     }
 
 
     pub fn timeout(&self) -> Option<Duration> {
-        self.qconn.timeout()
+        [self.keepalive_deadline(), self.early_data_deadline]
+            .into_iter()
+            .flatten()
+            .map(|t| t.saturating_duration_since(Instant::now()))
+            .chain(self.qconn.timeout())
+            .min()
+    }
+
+
+    /// Start waiting to know whether the server accepted early data, when the
+    /// handshake has completed. quiche does not tell that, but data that the
+    /// server rejects is retransmitted once it is declared lost, within about
+    /// one probe timeout. Early data is therefore reported as accepted if no
+    /// stream data has been retransmitted in twice that time. Early data can
+    /// only be accepted when the session was resumed.
+    fn start_early_data_check(&mut self, resumed: bool) {
+        if !self.clients.values().any(Client::is_in_early_data) {
+            return;
+        }
+        if !resumed || self.early_bytes == 0 {
+            self.report_early_data(false);
+            return;
+        }
+        let path = self.qconn.path_stats().next();
+        let pto = path.map_or(Duration::ZERO, |p| p.rtt + 4 * p.rttvar) + MAX_ACK_DELAY;
+        self.early_data_deadline = Some(Instant::now() + 2 * pto);
+    }
+
+
+    /// Report early data as not accepted as soon as stream data has been
+    /// retransmitted, or as accepted if the wait is over without that.
+    fn check_early_data(&mut self) {
+        let deadline = match self.early_data_deadline {
+            Some(deadline) => deadline,
+            None => return,
+        };
+        let retransmitted = self.qconn.stats().stream_retrans_bytes > 0;
+        if retransmitted || Instant::now() >= deadline {
+            self.early_data_deadline = None;
+            self.report_early_data(!retransmitted);
+        }
+    }
+
+
+    fn report_early_data(&mut self, accepted: bool) {
+        debug!("Early data accepted: {}", accepted);
+        for client in self.clients.values_mut() {
+            if client.is_in_early_data() {
+                client.early_data_done(accepted);
+            }
+        }
+    }
+
+
+    /// Returns when keepalive packet should be sent, if keepalive is enabled.
+    /// Connection is kept alive while it has clients.
+    fn keepalive_deadline(&self) -> Option<Instant> {
+        if !matches!(self.state, State::Established) || self.clients.is_empty() {
+            return None;
+        }
+        self.keepalive.map(|k| self.last_send + k)
     }
 
 
@@ -274,28 +734,71 @@ impl Connection {
     }
 
 
-    fn resolve_address(address: &str) -> Result<std::net::SocketAddr, String> {
-        let mut addrs = match address.to_socket_addrs() {
-            Ok(addrs) => {
-                addrs
+    /// Free tokens of the connection and its clients, when the connection is
+    /// dropped.
+    pub fn cleanup(&self, tokenmanager: &mut TokenManager) {
+        for client in self.clients.values() {
+            client.cleanup(tokenmanager);
+        }
+        tokenmanager.free_token(self.token);
+    }
+
+
+    /// Check the server certificate against pins and the destination address.
+    fn check_certificate(&self) -> Result<(), Error> {
+        check_pins(&self.pins, self.qconn.peer_cert())?;
+        match self.verify_address {
+            Some(ip) => check_ip_address(ip, self.qconn.peer_cert()),
+            None => Ok(()),
+        }
+    }
+
+
+    /// Send error that closed the connection to all clients. Clients are told
+    /// only once, however many times the closing is noticed.
+    fn report_close(&mut self, err: &Error) {
+        self.close_reported = true;
+        for client in self.clients.values_mut() {
+            client.send_error(err);
+        }
+    }
+
+
+    /// Error to report to clients when connection has been closed.
+    fn close_error(&self) -> Error {
+        let err = match self.qconn.peer_error().or(self.qconn.local_error()) {
+            Some(err) => err,
+            None => {
+                let reason = if self.qconn.is_timed_out() { "timed out" } else { "closed" };
+                if self.qconn.is_established() {
+                    return Error::ConnectionClosed { code: 0, reason: reason.to_string() };
+                }
+                return Error::Handshake(format!("Connection {}", reason));
             },
-            Err(e) => {
-                return Err(format!("Error resolving address '{}': {}", address, e));
-            }
         };
-        // TODO: needs to be redesigned. Need to check if first datagram is acknowledged,
-        // and rotate to new if not.
-        // For now only IPv4 addresses are therefore accepted. Fix later.
-        let addr = addrs.find(|&x| x.is_ipv4());
-        match addr {
-            Some(a) => Ok(a),
-            None => Err(format!("Could not find acceptable address for: {}", address))
+        let reason = String::from_utf8_lossy(&err.reason).to_string();
+        // CRYPTO_ERROR codes carry the TLS alert in the lowest byte.
+        if !err.is_app && (0x100..0x200).contains(&err.error_code) {
+            let alert = err.error_code - 0x100;
+            return match alert {
+                // no_application_protocol
+                120 => Error::AlpnMismatch(format!(
+                    "Server does not support '{}'", self.key.app_proto
+                )),
+                // bad_certificate ... certificate_unknown, unknown_ca
+                42..=46 | 48 => Error::TlsVerification(format!("TLS alert {} {}", alert, reason)),
+                _ => Error::Handshake(format!("TLS alert {} {}", alert, reason)),
+            };
         }
+        if !self.qconn.is_established() {
+            return Error::Handshake(format!("Error code {} {}", err.error_code, reason));
+        }
+        Error::ConnectionClosed { code: err.error_code, reason }
     }
 
 
     fn send_data(&mut self) {
-        let mut out = [0; MAX_DATAGRAM_SIZE];
+        let mut out = [0; MAX_UDP_PAYLOAD_SIZE];
 
         // Generate outgoing QUIC packets and send them on the UDP socket, until
         // quiche reports that there are no more packets to be sent.
@@ -316,6 +819,7 @@ impl Connection {
                 }
                 panic!("send() failed: {:?}", e);
             }
+            self.last_send = Instant::now();
 
             debug!("written to socket {} bytes", write);
         }
@@ -323,48 +827,223 @@ impl Connection {
 
 
     fn handle_established(&mut self) {
-        let mut buf = [0; 65535];
-
         // Process all readable streams.
-        for stream_id in self.qconn.readable() {
-            while let Ok((read, fin)) =
-                self.qconn.stream_recv(stream_id, &mut buf)
-            {
-                let stream_buf = &buf[..read];
-                debug!(
-                    "{} stream {} has {} bytes (fin? {})",
-                    self.qconn.trace_id(),
-                    stream_id,
-                    stream_buf.len(),
-                    fin
-                );
-                println!("Received: {}", String::from_utf8(stream_buf.to_vec()).unwrap());
-
-                if !self.received_data.contains_key(&stream_id) {
-                    self.received_data.insert(stream_id, Vec::new());
-                }
-                let v = self.received_data.get_mut(&stream_id).unwrap();
-                v.append(&mut stream_buf.to_vec());
+        let readable: Vec<u64> = self.qconn.readable().collect();
+        for stream_id in readable {
+            self.recv_stream(stream_id);
+        }
+    }
+
+
+    /// Read data from QUIC stream and deliver it to its client, followed by
+    /// FIN or RESET when peer has finished or reset the stream. Reading stops
+    /// when the receive credit of the client runs out or the client socket
+    /// buffer is full, and the rest of the data stays in the QUIC stream, so
+    /// that flow control pushes back on the peer.
+    fn recv_stream(&mut self, stream_id: u64) {
+        let token = match self.streams.get(&stream_id) {
+            Some(token) => *token,
+            None if is_server_initiated(stream_id) => match self.route_stream(stream_id) {
+                Some(token) => token,
+                None => return,
+            },
+            None => {
+                info!("Could not find client for stream {}", stream_id);
+                return;
+            },
+        };
+        let client = self.clients.get_mut(&token).unwrap();
+        let mut buf = [0; MAX_DATA_LEN];
+        while !client.is_congested() {
+            // Without credit, FIN or reset can still be noticed with empty
+            // buffer.
+            let n = std::cmp::min(client.recv_credit(stream_id), MAX_DATA_LEN as u64) as usize;
+            match self.qconn.stream_recv(stream_id, &mut buf[..n]) {
+                Ok((read, fin)) => {
+                    debug!(
+                        "{} stream {} has {} bytes (fin? {})",
+                        self.qconn.trace_id(),
+                        stream_id,
+                        read,
+                        fin
+                    );
+                    if read > 0 {
+                        client.deliver_data(stream_id, &buf[..read]);
+                    }
+                    if fin {
+                        client.send_fin(stream_id);
+                        break;
+                    }
+                    if read == 0 {
+                        break;
+                    }
+                },
+                Err(quiche::Error::StreamReset(code)) => {
+                    debug!("Peer reset stream {} with code {}", stream_id, code);
+                    client.send_reset(stream_id, code);
+                    break;
+                },
+                Err(_) => break,
             }
-            match self.clients.get_mut(&stream_id) {
-                Some(client) => {
-                    let v = self.received_data.get_mut(&stream_id).unwrap();
-                    match client.deliver_data(v) {
-                        Ok(n) => {
-                            v.drain(0..n);
-                        },
-                        Err(e) => {
-                            error!("Got error delivering to client: {}", e);
-                        },
-                    };
+        }
+    }
+
+
+    /// Clients that accept streams of the kind opened by the server.
+    fn acceptors(&mut self, bidi: bool) -> &mut VecDeque<Token> {
+        if bidi { &mut self.bidi_acceptors } else { &mut self.uni_acceptors }
+    }
+
+
+    /// Give stream opened by the server to next client that accepts streams of
+    /// its kind. Clients take turns, so that streams are spread among them. If
+    /// no client accepts streams of the kind, the stream waits in QUIC
+    /// connection until one does.
+    fn route_stream(&mut self, stream_id: u64) -> Option<Token> {
+        let acceptors = self.acceptors(is_bidi(stream_id));
+        let token = match acceptors.pop_front() {
+            Some(token) => token,
+            None => {
+                debug!("No client accepts stream {}", stream_id);
+                return None;
+            },
+        };
+        acceptors.push_back(token);
+        debug!(
+            "Giving {} stream {} to {:?}",
+            if is_bidi(stream_id) { "bidirectional" } else { "unidirectional" },
+            stream_id,
+            token
+        );
+        self.clients.get_mut(&token).unwrap().new_stream(stream_id);
+        self.streams.insert(stream_id, token);
+        Some(token)
+    }
+
+
+    /// Assign datagram flow to client, if datagrams are enabled and server
+    /// supports them.
+    fn open_flow(&mut self, token: Token) {
+        let client = self.clients.get_mut(&token).unwrap();
+        if !self.datagrams || self.qconn.dgram_max_writable_len().is_none() {
+            client.send_error(&Error::DatagramUnsupported);
+            return;
+        }
+        let flow_id = match client.flow() {
+            Some(flow_id) => flow_id,
+            None => {
+                let flow_id = self.next_flow_id;
+                self.next_flow_id += 1;
+                self.flows.insert(flow_id, token);
+                flow_id
+            },
+        };
+        debug!("Datagram flow {} for {:?}", flow_id, token);
+        client.open_flow(flow_id);
+    }
+
+
+    /// Send datagram of client, prefixed with its flow ID. Datagrams that do not
+    /// fit in the path MTU or the send queue are dropped.
+    fn send_datagram(&mut self, token: Token, data: &[u8]) {
+        let client = self.clients.get_mut(&token).unwrap();
+        let flow_id = match client.flow() {
+            Some(flow_id) => flow_id,
+            None => {
+                client.send_error(&Error::Protocol("Datagram without flow".to_string()));
+                return;
+            },
+        };
+        let mut buf = Vec::with_capacity(8 + data.len());
+        put_varint(&mut buf, flow_id);
+        buf.extend_from_slice(data);
+        match self.qconn.dgram_send(&buf) {
+            Ok(()) => (),
+            Err(quiche::Error::InvalidState) => client.send_error(&Error::DatagramUnsupported),
+            Err(e) => debug!("Dropping datagram of flow {}: {:?}", flow_id, e),
+        }
+    }
+
+
+    /// Deliver datagrams from the server to clients that own their flows.
+    fn recv_datagrams(&mut self) {
+        let mut buf = [0; MAX_UDP_PAYLOAD_SIZE];
+        loop {
+            let len = match self.qconn.dgram_recv(&mut buf) {
+                Ok(len) => len,
+                Err(quiche::Error::BufferTooShort) => {
+                    // quiche drops the datagram that does not fit
+                    debug!("Dropping too long datagram");
+                    continue;
                 },
+                Err(_) => break,
+            };
+            let (flow_id, n) = match get_varint(&buf[..len]) {
+                Some(v) => v,
                 None => {
-                    info!("Could not find client for stream {}", stream_id);
+                    debug!("Dropping datagram without flow ID");
+                    continue;
                 },
             };
-            // TODO: remove processed data from connection
+            match self.flows.get(&flow_id).and_then(|t| self.clients.get_mut(t)) {
+                Some(client) => client.deliver_datagram(&buf[n..len]),
+                None => debug!("Dropping datagram of unknown flow {}", flow_id),
+            }
         }
     }
+
+
+    /// Tell clients whose peer has sent STOP_SENDING on their stream.
+    fn check_stopped(&mut self) {
+        let stopped: Vec<(u64, u64)> = self.qconn.writable()
+            .filter_map(|id| match self.qconn.stream_capacity(id) {
+                Err(quiche::Error::StreamStopped(code)) => Some((id, code)),
+                _ => None,
+            })
+            .collect();
+        for (stream_id, code) in stopped {
+            self.notify_stopped(stream_id, code);
+        }
+    }
+
+
+    /// Tell client once that peer has stopped reading its stream.
+    fn notify_stopped(&mut self, stream_id: u64, code: u64) {
+        if !self.stop_notified.insert(stream_id) {
+            return;
+        }
+        debug!("Peer stopped reading stream {} with code {}", stream_id, code);
+        if let Some(client) = self.streams.get(&stream_id).and_then(|t| self.clients.get_mut(t)) {
+            client.send_stop_sending(stream_id, code);
+        }
+    }
+
+
+    /// Abort one direction of a stream on request of its client.
+    fn shutdown_stream(&mut self, stream_id: u64, direction: quiche::Shutdown, code: u64) {
+        debug!("Shutting down stream {} with code {}", stream_id, code);
+        self.qconn.stream_shutdown(stream_id, direction, code).ok();
+    }
+
+
+    /// Close stream of a client that has left. If the client had finished
+    /// sending, its FIN is already on the stream. Otherwise the client went away
+    /// in the middle of sending, and the stream is reset. If peer is still
+    /// sending, it is asked to stop.
+    fn close_stream(&mut self, stream_id: u64, finished: bool) {
+        if !finished {
+            debug!("Resetting stream {}", stream_id);
+            self.qconn.stream_shutdown(
+                stream_id, quiche::Shutdown::Write, CLIENT_GONE_ERROR_CODE
+            ).ok();
+        }
+        if !self.qconn.stream_finished(stream_id) {
+            self.qconn.stream_shutdown(
+                stream_id, quiche::Shutdown::Read, CLIENT_GONE_ERROR_CODE
+            ).ok();
+        }
+        self.stop_notified.remove(&stream_id);
+    }
 }
 
 
@@ -375,26 +1054,389 @@ fn hex_dump(buf: &[u8]) -> String {
 }
 
 
-fn set_quic_config(appname: &str) -> Config {
+fn set_quic_config(
+    key: &ConnectionKey,
+    tls: &TlsSettings,
+    transport: &TransportParams,
+) -> Result<Config, Error> {
     let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
 
-    config.verify_peer(false);
+    tls.configure(&mut config, key.identity.as_deref())?;
 
     config.set_application_protos(&[
-            appname.as_bytes(),
+            key.app_proto.as_bytes(),
         ]).unwrap();
 
-    config.set_max_idle_timeout(50000);
-    config.set_max_recv_udp_payload_size(MAX_DATAGRAM_SIZE);
-    config.set_max_send_udp_payload_size(MAX_DATAGRAM_SIZE);
-    config.set_initial_max_data(10_000_000);
-    config.set_initial_max_stream_data_bidi_local(1_000_000);
-    config.set_initial_max_stream_data_bidi_remote(1_000_000);
-    config.set_initial_max_stream_data_uni(1_000_000);
-    config.set_initial_max_streams_bidi(100);
-    config.set_initial_max_streams_uni(100);
+    config.set_max_idle_timeout(transport.idle_timeout_ms);
+    config.set_max_recv_udp_payload_size(transport.max_udp_payload_size);
+    config.set_max_send_udp_payload_size(transport.max_udp_payload_size);
+    config.set_initial_max_data(transport.max_data);
+    config.set_initial_max_stream_data_bidi_local(transport.max_stream_data_bidi_local);
+    config.set_initial_max_stream_data_bidi_remote(transport.max_stream_data_bidi_remote);
+    config.set_initial_max_stream_data_uni(transport.max_stream_data_uni);
+    config.set_initial_max_streams_bidi(transport.max_streams_bidi);
+    config.set_initial_max_streams_uni(transport.max_streams_uni);
     config.set_disable_active_migration(true);
-    config.enable_early_data();
+    config.set_cc_algorithm(transport.congestion_control.algorithm());
+    config.enable_hystart(transport.hystart);
+    config.enable_pacing(transport.pacing);
+    // Early data would be sent before the server certificate is checked
+    // against pins or the address.
+    if tls.pins_for(&key.host()).is_empty()
+        && !tls.checks_address(key.server_name.as_deref(), &key.host())
+    {
+        config.enable_early_data();
+    }
+    // Connection is shared, so when datagrams are enabled, they are enabled
+    // for any client that wants them.
+    config.enable_dgram(
+        transport.datagrams,
+        transport.datagram_queue_len,
+        transport.datagram_queue_len,
+    );
+
+    Ok(config)
+}
 
-    config
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::SocketAddr,
+        thread::sleep,
+    };
+
+    use clap::Parser;
+    use quic_cm::common::codec::Frame;
+
+    use super::*;
+    use crate::{
+        config::Args,
+        pool::{Pool, PoolPolicy},
+    };
+
+    /// QUIC server on a UDP socket, that accepts early data of resumed
+    /// sessions. Server finishes each stream that the client
+    /// finishes, so that the client can open more streams.
+    struct TestServer {
+        socket: std::net::UdpSocket,
+        config: quiche::Config,
+        conn: Option<quiche::Connection>,
+    }
+
+    impl TestServer {
+        /// Server with the certificate of the test server in quic-cm-lib/tests.
+        fn new(max_streams_bidi: u64) -> TestServer {
+            TestServer::with_cert(
+                max_streams_bidi,
+                "../quic-cm-lib/tests/cert.crt",
+                "../quic-cm-lib/tests/cert.key",
+            )
+        }
+
+
+        fn with_cert(max_streams_bidi: u64, cert: &str, key: &str) -> TestServer {
+            let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).unwrap();
+            config.load_cert_chain_from_pem_file(cert).unwrap();
+            config.load_priv_key_from_pem_file(key).unwrap();
+            config.set_application_protos(&[b"test"]).unwrap();
+            config.set_initial_max_data(1_000_000);
+            config.set_initial_max_stream_data_bidi_remote(100_000);
+            config.set_initial_max_streams_bidi(max_streams_bidi);
+            config.enable_early_data();
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.set_nonblocking(true).unwrap();
+            TestServer { socket, config, conn: None }
+        }
+
+
+        fn addr(&self) -> SocketAddr {
+            self.socket.local_addr().unwrap()
+        }
+
+
+        /// Process packets from client, and send packets that the server has.
+        fn exchange(&mut self) {
+            let mut buf = [0; 65535];
+            let local = self.addr();
+            while let Ok((len, from)) = self.socket.recv_from(&mut buf) {
+                let conn = match &mut self.conn {
+                    Some(conn) => conn,
+                    None => {
+                        let scid = quiche::ConnectionId::from_ref(&[1; 16]);
+                        let conn = quiche::accept(&scid, None, local, from, &mut self.config);
+                        self.conn.insert(conn.unwrap())
+                    },
+                };
+                let _ = conn.recv(&mut buf[..len], quiche::RecvInfo { from, to: local });
+            }
+            let conn = match &mut self.conn {
+                Some(conn) => conn,
+                None => return,
+            };
+            for stream_id in conn.readable() {
+                while conn.stream_recv(stream_id, &mut buf).is_ok() {}
+                if conn.stream_finished(stream_id) {
+                    let _ = conn.stream_send(stream_id, b"", true);
+                }
+            }
+            while let Ok((len, send_info)) = conn.send(&mut buf) {
+                self.socket.send_to(&buf[..len], send_info.to).unwrap();
+            }
+        }
+    }
+
+
+    fn settings() -> Settings {
+        let args = Args::try_parse_from(["quic-cm-manager", "--tls-no-verify"]).unwrap();
+        Settings::load(args).unwrap()
+    }
+
+
+    fn key(peer: SocketAddr) -> ConnectionKey {
+        ConnectionKey {
+            peer,
+            app_proto: "test".to_string(),
+            server_name: None,
+            identity: None,
+        }
+    }
+
+
+    /// Connection for `key`, with one client. Returns the connection, the
+    /// client's end of its socket and its token.
+    fn connect(
+        key: &ConnectionKey,
+        settings: &Settings,
+        tokenmanager: &mut TokenManager,
+        poll: &mut Poll,
+    ) -> (Connection, UnixStream, Token) {
+        let mut conn = Connection::new(key, settings, None, None, tokenmanager, poll).unwrap();
+        let (socket, peer) = UnixStream::pair().unwrap();
+        peer.set_nonblocking(true).unwrap();
+        let token = tokenmanager.allocate_token();
+        conn.add_client(socket, FrameDecoder::new(), poll, token, false, tokenmanager);
+        (conn, peer, token)
+    }
+
+
+    /// Pass packets between connection and server for a few round trips.
+    fn exchange(conn: &mut Connection, server: &mut TestServer) {
+        for _ in 0..5 {
+            conn.send_data();
+            sleep(Duration::from_millis(5));
+            server.exchange();
+            sleep(Duration::from_millis(5));
+            conn.process_datagram();
+        }
+    }
+
+
+    /// Frames that manager has sent to the client.
+    fn frames(peer: &mut UnixStream) -> Vec<Frame> {
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; 4096];
+        while let Ok(n @ 1..) = peer.read(&mut buf) {
+            decoder.feed(&buf[..n]);
+        }
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+
+    /// IDs of streams that manager has told the client to be opened.
+    fn opened(peer: &mut UnixStream) -> Vec<u64> {
+        frames(peer)
+            .into_iter()
+            .filter_map(|frame| match frame {
+                Frame::Opened { stream_id } => Some(stream_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+
+    #[test]
+    fn test_open_pending() {
+        let mut tokenmanager = TokenManager::new();
+        let mut poll = Poll::new().unwrap();
+        let mut server = TestServer::new(2);
+        let key = key(server.addr());
+        let (mut conn, mut peer, token) = connect(&key, &settings(), &mut tokenmanager, &mut poll);
+        // Streams wait for the handshake
+        conn.open_stream(token, true);
+        assert_eq!(conn.pending_opens.len(), 1);
+        assert!(opened(&mut peer).is_empty());
+
+        exchange(&mut conn, &mut server);
+        assert!(matches!(conn.state, State::Established));
+        conn.open_stream(token, true);
+        conn.open_stream(token, true);
+        // Stream IDs start from 0, so that no stream is opened implicitly,
+        // and the third stream waits for the peer's stream limit.
+        assert_eq!(opened(&mut peer), [0, 4]);
+        assert_eq!(conn.pending_opens.len(), 1);
+        assert_eq!(conn.qconn.peer_streams_left_bidi(), 0);
+        assert_eq!(conn.num_streams(), 3);
+
+        // Finishing the streams makes the server raise its limit
+        for stream_id in [0, 4] {
+            conn.qconn.stream_send(stream_id, b"", true).unwrap();
+        }
+        exchange(&mut conn, &mut server);
+        assert_eq!(opened(&mut peer), [8]);
+        assert!(conn.pending_opens.is_empty());
+    }
+
+
+    #[test]
+    fn test_saturated() {
+        let mut tokenmanager = TokenManager::new();
+        let mut poll = Poll::new().unwrap();
+        let mut server = TestServer::new(1);
+        let key = key(server.addr());
+        let (mut conn, _peer, token) = connect(&key, &settings(), &mut tokenmanager, &mut poll);
+        let depth = SpillPolicy { queue_depth: Some(1), ..Default::default() };
+        let wait = SpillPolicy { max_wait: Some(Duration::ZERO), ..Default::default() };
+        assert!(!conn.is_saturated(&depth));
+        assert!(!conn.is_saturated(&SpillPolicy::default()));
+
+        // Streams waiting for the handshake count in the queue, but not
+        // their waiting time.
+        conn.open_stream(token, true);
+        assert!(conn.is_saturated(&depth));
+        assert!(!conn.is_saturated(&wait));
+
+        // The first stream is opened, the second waits for the stream limit
+        exchange(&mut conn, &mut server);
+        assert!(!conn.is_saturated(&depth));
+        assert!(!conn.is_saturated(&wait));
+        conn.open_stream(token, true);
+        assert!(conn.is_saturated(&depth));
+        assert!(conn.is_saturated(&wait));
+        let long_wait = SpillPolicy { max_wait: Some(Duration::from_secs(60)), ..wait };
+        assert!(!conn.is_saturated(&long_wait));
+        // Spilling is disabled without limits
+        assert!(!conn.is_saturated(&SpillPolicy::default()));
+
+        // Saturated connection is spilled over to a new one
+        let mut pool = Pool::new();
+        assert!(pool.select_or_open(PoolPolicy::First, &depth, || Ok(conn)).is_ok());
+        let mut spilled = false;
+        let _ = pool.select_or_open(PoolPolicy::First, &depth, || {
+            spilled = true;
+            Err(Error::Protocol("not opened".to_string()))
+        });
+        assert!(spilled);
+        let mut spilled = false;
+        let _ = pool.select_or_open(PoolPolicy::First, &SpillPolicy::default(), || {
+            spilled = true;
+            Err(Error::Protocol("not opened".to_string()))
+        });
+        assert!(!spilled);
+    }
+
+
+    #[test]
+    fn test_send_error() {
+        let mut tokenmanager = TokenManager::new();
+        let mut poll = Poll::new().unwrap();
+        // Socket may not send to broadcast address
+        let key = key("255.255.255.255:7878".parse().unwrap());
+        let res = Connection::new(&key, &settings(), None, None, &mut tokenmanager, &mut poll);
+        assert!(matches!(res, Err(Error::Io(_))));
+    }
+
+
+    #[test]
+    fn test_verify_address() {
+        // Certificates are issued by testdata/ca.crt for other.example, and
+        // for 127.0.0.1 and ::1.
+        let args = ["quic-cm-manager", "--tls-ca-file", "testdata/ca.crt"];
+        let settings = Settings::load(Args::try_parse_from(args).unwrap()).unwrap();
+        let cases = [
+            ("name", None, false),
+            ("name", Some("other.example"), true),
+            ("name", Some("wrong.example"), false),
+            ("ip", None, true),
+        ];
+        for (cert, server_name, accepted) in cases {
+            let mut tokenmanager = TokenManager::new();
+            let mut poll = Poll::new().unwrap();
+            let crt = format!("testdata/{}.crt", cert);
+            let mut server = TestServer::with_cert(1, &crt, &format!("testdata/{}.key", cert));
+            let key = ConnectionKey {
+                server_name: server_name.map(str::to_string),
+                ..key(server.addr())
+            };
+            let (mut conn, mut peer, _) = connect(&key, &settings, &mut tokenmanager, &mut poll);
+            exchange(&mut conn, &mut server);
+            assert_eq!(matches!(conn.state, State::Established), accepted, "{} {:?}", cert, key);
+            // TLS rejects a wrong server name in the handshake, manager rejects
+            // the address when the handshake completes.
+            if server_name.is_none() {
+                let rejected = frames(&mut peer)
+                    .iter()
+                    .any(|frame| matches!(frame, Frame::Error(Error::TlsVerification(_))));
+                assert_eq!(rejected, !accepted, "{} {:?}", cert, key);
+            }
+        }
+    }
+
+
+    #[test]
+    fn test_early_data_accepted() {
+        let mut tokenmanager = TokenManager::new();
+        let mut poll = Poll::new().unwrap();
+        let mut server = TestServer::new(10);
+        let key = key(server.addr());
+        let (mut conn, _peer, _) = connect(&key, &settings(), &mut tokenmanager, &mut poll);
+        exchange(&mut conn, &mut server);
+        assert!(matches!(conn.state, State::Established));
+        let session = conn.new_session().unwrap();
+
+        // Resume the session in a new connection, with data sent in 0-RTT
+        server.conn = None;
+        let settings = settings();
+        let mut conn =
+            Connection::new(&key, &settings, None, Some(&session), &mut tokenmanager, &mut poll)
+                .unwrap();
+        let (socket, mut peer) = UnixStream::pair().unwrap();
+        peer.set_nonblocking(true).unwrap();
+        let token = tokenmanager.allocate_token();
+        conn.add_client(socket, FrameDecoder::new(), &mut poll, token, true, &mut tokenmanager);
+        conn.open_stream(token, true);
+        let mut frames_seen = frames(&mut peer);
+        assert!(matches!(frames_seen[..], [
+            Frame::ConnectOk { early_data: true, .. },
+            Frame::Opened { stream_id: 0 },
+            ..
+        ]));
+        let mut out = Vec::new();
+        Frame::Data { stream_id: 0, data: b"early".to_vec() }.encode(&mut out);
+        Frame::Fin { stream_id: 0 }.encode(&mut out);
+        peer.write_all(&out).unwrap();
+        conn.serve_client(token, &mut tokenmanager);
+        assert!(conn.early_bytes > 0);
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < deadline {
+            exchange(&mut conn, &mut server);
+            frames_seen.extend(frames(&mut peer));
+            if frames_seen.iter().any(|frame| matches!(frame, Frame::EarlyData { .. })) {
+                break;
+            }
+        }
+        assert!(conn.qconn.is_resumed());
+        let early_data = frames_seen.iter().find_map(|frame| match frame {
+            Frame::EarlyData { accepted } => Some(*accepted),
+            _ => None,
+        });
+        assert_eq!(early_data, Some(true));
+        assert_eq!(conn.qconn.stats().stream_retrans_bytes, 0);
+    }
 }
@@ -0,0 +1,244 @@
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use quic_cm::{Error, common::DEFAULT_PORT};
+
+
+/// Identifies connections that clients can share. Clients with equal keys
+/// share connections, and others get connections of their own, so that for
+/// example different application protocols to the same server do not conflict.
+/// The key is built from the resolved address and normalized server name, so
+/// that different spellings of the same destination share connections. Clients
+/// share a connection only if they authenticate with the same client identity.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ConnectionKey {
+    /// Resolved address of the server.
+    pub peer: SocketAddr,
+    /// Application protocol negotiated with ALPN.
+    pub app_proto: String,
+    /// Server name, if destination was given as DNS name.
+    pub server_name: Option<String>,
+    /// Name of the client certificate used on the connection.
+    pub identity: Option<String>,
+}
+
+
+/// Connection request of a client, from CONNECT.
+pub struct ConnectRequest {
+    pub address: String,
+    pub app_proto: String,
+    pub server_name: Option<String>,
+    pub identity: Option<String>,
+    pub early_data: bool,
+    /// Congestion control algorithm that client prefers for a new connection.
+    pub congestion_control: Option<String>,
+}
+
+impl ConnectionKey {
+    /// Build key for destination requested in CONNECT. Server name is the one
+    /// that the client gave, otherwise the host in address if it is a DNS name.
+    /// Identity is not set, because it depends on settings and the user.
+    pub fn new(request: &ConnectRequest) -> Result<ConnectionKey, Error> {
+        let (host, port) = split_address(&request.address)?;
+        let server_name = match &request.server_name {
+            Some(name) => Some(server_name(name).ok_or_else(|| {
+                Error::Dns(format!("Invalid server name '{}'", name))
+            })?),
+            None => server_name(host),
+        };
+        Ok(ConnectionKey {
+            peer: resolve_address(host, port)?,
+            app_proto: request.app_proto.clone(),
+            server_name,
+            identity: None,
+        })
+    }
+
+
+    /// Host that the destination is known by in settings: server name, or IP
+    /// address if destination was not given as DNS name.
+    pub fn host(&self) -> String {
+        match &self.server_name {
+            Some(name) => name.clone(),
+            None => self.peer.ip().to_string(),
+        }
+    }
+}
+
+
+/// Split destination address to host and port. Address is of form
+/// `<host>:<port>`, where IPv6 address is enclosed in brackets, and port can be
+/// omitted, in which case the default port is used.
+fn split_address(address: &str) -> Result<(&str, u16), Error> {
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        match rest.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) if port.starts_with(':') => (host, Some(&port[1..])),
+            _ => return Err(Error::Dns(format!("Malformed address '{}'", address))),
+        }
+    } else {
+        match address.split_once(':') {
+            // IPv6 address without brackets cannot have port
+            Some((_, rest)) if rest.contains(':') => (address, None),
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
+        }
+    };
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| {
+            Error::Dns(format!("Invalid port in address '{}'", address))
+        })?,
+        None => DEFAULT_PORT,
+    };
+    if host.is_empty() {
+        return Err(Error::Dns(format!("No host in address '{}'", address)));
+    }
+    Ok((host, port))
+}
+
+
+/// Returns normalized server name, unless `host` is an IP address or empty.
+/// DNS names are case insensitive, and may end with dot.
+fn server_name(host: &str) -> Option<String> {
+    let name = host.trim_end_matches('.');
+    match name.parse::<IpAddr>() {
+        Ok(_) => None,
+        Err(_) if name.is_empty() => None,
+        Err(_) => Some(name.to_ascii_lowercase()),
+    }
+}
+
+
+/// Resolve host to one address. IPv4 address is preferred, and IPv6 address is
+/// used if host has no IPv4 address.
+fn resolve_address(host: &str, port: u16) -> Result<SocketAddr, Error> {
+    let addrs: Vec<SocketAddr> = match (host, port).to_socket_addrs() {
+        Ok(addrs) => {
+            addrs.collect()
+        },
+        Err(e) => {
+            return Err(Error::Dns(format!("Error resolving address '{}': {}", host, e)));
+        }
+    };
+    // TODO: needs to be redesigned. Need to check if first datagram is acknowledged,
+    // and rotate to new if not.
+    // For now the first IPv4 address, or the first IPv6 address, is used.
+    let addr = addrs.iter().find(|x| x.is_ipv4()).or(addrs.first()).copied();
+    match addr {
+        Some(a) => Ok(a),
+        None => Err(Error::Dns(format!("Could not find acceptable address for: {}", host)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(address: &str, server_name: Option<&str>) -> ConnectRequest {
+        ConnectRequest {
+            address: address.to_string(),
+            app_proto: "h3".to_string(),
+            server_name: server_name.map(str::to_string),
+            identity: None,
+            early_data: false,
+            congestion_control: None,
+        }
+    }
+
+
+    fn key(address: &str, server_name: Option<&str>) -> ConnectionKey {
+        ConnectionKey::new(&request(address, server_name)).unwrap()
+    }
+
+
+    #[test]
+    fn test_split_address() {
+        let cases = [
+            ("example.com:443", Some(("example.com", 443))),
+            ("example.com", Some(("example.com", DEFAULT_PORT))),
+            ("192.0.2.1:4433", Some(("192.0.2.1", 4433))),
+            ("192.0.2.1", Some(("192.0.2.1", DEFAULT_PORT))),
+            ("[::1]:7879", Some(("::1", 7879))),
+            ("[::1]", Some(("::1", DEFAULT_PORT))),
+            ("::1", Some(("::1", DEFAULT_PORT))),
+            ("2001:db8::1", Some(("2001:db8::1", DEFAULT_PORT))),
+            ("", None),
+            (":443", None),
+            ("[]:443", None),
+            ("[::1", None),
+            ("[::1]443", None),
+            ("example.com:", None),
+            ("example.com:https", None),
+            ("example.com:65536", None),
+        ];
+        for (address, expected) in cases {
+            assert_eq!(split_address(address).ok(), expected, "{}", address);
+        }
+    }
+
+
+    #[test]
+    fn test_server_name() {
+        let cases = [
+            ("example.com", Some("example.com")),
+            ("Example.COM", Some("example.com")),
+            ("example.com.", Some("example.com")),
+            ("localhost", Some("localhost")),
+            ("127.0.0.1", None),
+            ("::1", None),
+            (".", None),
+            ("", None),
+        ];
+        for (host, expected) in cases {
+            assert_eq!(server_name(host).as_deref(), expected, "{}", host);
+        }
+    }
+
+
+    #[test]
+    fn test_address_families() {
+        let addr = key("127.0.0.1", None).peer;
+        assert_eq!(addr, "127.0.0.1:7878".parse().unwrap());
+        let addr = key("[::1]:443", None).peer;
+        assert_eq!(addr, "[::1]:443".parse().unwrap());
+        // IPv4 is preferred for names that have both
+        assert!(key("localhost", None).peer.is_ipv4());
+    }
+
+
+    #[test]
+    fn test_shared_keys() {
+        let same = [
+            (("127.0.0.1", None), ("127.0.0.1:7878", None)),
+            (("[::1]", None), ("[::1]:7878", None)),
+            (("localhost", None), ("LOCALHOST:7878", None)),
+            (("localhost", None), ("127.0.0.1", Some("localhost"))),
+            (("127.0.0.1", Some("Localhost.")), ("127.0.0.1", Some("localhost"))),
+        ];
+        for ((a, a_name), (b, b_name)) in same {
+            assert_eq!(key(a, a_name), key(b, b_name), "{} {}", a, b);
+        }
+        let different = [
+            // Destination given by address is not verified against a name
+            (("127.0.0.1", None), ("localhost", None)),
+            (("127.0.0.1", None), ("127.0.0.1:7879", None)),
+            (("127.0.0.1", None), ("[::1]", None)),
+            (("127.0.0.1", Some("a.example")), ("127.0.0.1", Some("b.example"))),
+        ];
+        for ((a, a_name), (b, b_name)) in different {
+            assert_ne!(key(a, a_name), key(b, b_name), "{} {}", a, b);
+        }
+        let mut other_proto = request("127.0.0.1", None);
+        other_proto.app_proto = "hq".to_string();
+        assert_ne!(key("127.0.0.1", None), ConnectionKey::new(&other_proto).unwrap());
+    }
+
+
+    #[test]
+    fn test_invalid_server_name() {
+        for name in ["192.0.2.1", "."] {
+            let result = ConnectionKey::new(&request("127.0.0.1", Some(name)));
+            assert!(matches!(result, Err(Error::Dns(_))), "{}", name);
+        }
+    }
+}
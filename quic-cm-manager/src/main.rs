@@ -1,15 +1,37 @@
 #[macro_use]
 extern crate log;
 
-use crate::manager::start_manager;
+use clap::Parser;
+
+use crate::{
+    config::{Args, Settings},
+    manager::start_manager,
+};
 
 
 fn main() {
-    env_logger::builder().format_timestamp_nanos().init();
-    start_manager();
+    let settings = match Settings::load(Args::parse()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("quic-cm-manager: {}", e);
+            std::process::exit(1);
+        },
+    };
+    let mut logger = env_logger::builder();
+    if let Some(level) = &settings.log_level {
+        logger.parse_filters(level);
+    }
+    logger.format_timestamp_nanos().init();
+    start_manager(settings);
 }
 
 mod client;
+mod config;
 mod connection;
+mod destination;
 mod manager;
 mod mio_tokens;
+mod pool;
+mod profile;
+mod session_cache;
+mod tls;
@@ -1,40 +1,71 @@
 use std::{
     collections::HashMap,
     fs::remove_file,
-    io::{Read, Write},
+    io::{ErrorKind, Read, Write},
     os::{
         fd::AsRawFd,
-        unix::net::UnixListener,
+        unix::net::{UnixListener, UnixStream},
     },
     time::Duration,
 };
 
 use mio::{
-    {Interest, Poll},
+    {Interest, Poll, Token},
     unix::SourceFd,
 };
 use mio_signals::{Signals, SignalSet, Signal};
+use nix::{
+    sys::socket::{getsockopt, sockopt::PeerCredentials},
+    unistd::Uid,
+};
 
-use quic_cm::common::QCM_CONTROL_SOCKET;
+use quic_cm::{
+    Error,
+    common::{
+        codec::{Frame, FrameDecoder, negotiate_version},
+    },
+};
 
 use crate::{
     client::Client,
+    config::{CongestionControl, Settings},
     connection::Connection,
+    destination::{ConnectRequest, ConnectionKey},
+    pool::Pool,
     mio_tokens::TokenManager,
+    session_cache::SessionCache,
+    tls::TlsSettings,
 };
 
 
-pub fn start_manager() {
+pub fn start_manager(settings: Settings) {
     let mut tokenmanager: TokenManager = TokenManager::new();
-    let mut connections: HashMap<String, Connection> = HashMap::new();
+    let mut connections: HashMap<ConnectionKey, Pool> = HashMap::new();
+    let mut new_clients: HashMap<Token, NewClient> = HashMap::new();
+    let mut sessions = SessionCache::new(settings.session_cache_dir.clone());
+    if !settings.tls.verify {
+        warn!("Server certificate verification is disabled");
+    }
     let mut events = mio::Events::with_capacity(1024);
     let mut poll = mio::Poll::new().unwrap();
     let sigset: SignalSet = Signal::Interrupt | Signal::Terminate;
     let mut signals = Signals::new(sigset).unwrap();
     let signal_token = tokenmanager.allocate_token();
 
-    let controlsocket = UnixListener::bind(QCM_CONTROL_SOCKET)
-        .unwrap();
+    let controlsocket = match UnixListener::bind(&settings.socket) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Could not bind control socket {}: {}", settings.socket.display(), e);
+            return;
+        },
+    };
+    // Listener is registered edge-triggered, so it is accepted from until it
+    // would block.
+    if let Err(e) = controlsocket.set_nonblocking(true) {
+        error!("Could not set control socket nonblocking: {}", e);
+        let _ = remove_file(&settings.socket);
+        return;
+    }
     let controltoken = tokenmanager.allocate_token();
 
     poll.registry()
@@ -50,19 +81,22 @@ pub fn start_manager() {
     while !terminate {
         // Set timer to connection with nearest timeout
         let mut timeout: Option<Duration> = None;
-        for connection in connections.values() {
-            if connection.timeout().is_some() {
-                if timeout.is_none() || Some(connection.timeout()) < Some(timeout) {
-                    timeout = connection.timeout();
-                }
+        for connection in connections.values().flat_map(Pool::iter) {
+            if connection.timeout().is_some()
+                && (timeout.is_none() || Some(connection.timeout()) < Some(timeout)) {
+                timeout = connection.timeout();
             }
         }
 
         poll.poll(&mut events, timeout).unwrap();
-        if events.is_empty() {
-            debug!("Timeout");
-            for connection in connections.values_mut() {
-                connection.process_events(None, &mut tokenmanager).unwrap();
+        // Timers are handled also when there are events, so that busy client
+        // sockets do not hold up loss recovery of the connections.
+        for connection in connections.values_mut().flat_map(Pool::iter_mut) {
+            if connection.timeout().is_some_and(|t| t.is_zero()) {
+                debug!("Timeout");
+                if let Err(e) = connection.process_events(None, &mut tokenmanager) {
+                    error!("Processing timeout failed: {}", e);
+                }
             }
         }
         for event in &events {
@@ -71,72 +105,224 @@ pub fn start_manager() {
                 terminate = true;
             }
             if event.token() == controltoken {
-                accept_incoming(&controlsocket,
-                                    &mut tokenmanager, &mut poll, &mut connections);
+                accept_incoming(&controlsocket, &mut tokenmanager, &mut poll, &mut new_clients);
+            }
+            if new_clients.contains_key(&event.token()) {
+                serve_new_client(event.token(), &mut new_clients, &mut tokenmanager, &mut poll,
+                                 &mut connections, &settings, &mut sessions);
             }
 
-            for connection in connections.values_mut() {
-                // TODO: handle errors
-                connection.process_events(Some(event), &mut tokenmanager).unwrap();
+            for connection in connections.values_mut().flat_map(Pool::iter_mut) {
+                if let Err(e) = connection.process_events(Some(event), &mut tokenmanager) {
+                    error!("Processing event failed: {}", e);
+                }
+            }
+        }
+        // Keep new session tickets for later connections
+        for connection in connections.values_mut().flat_map(Pool::iter_mut) {
+            if let Some(session) = connection.new_session() {
+                sessions.store(connection.key(), session);
             }
         }
         // Remove all closed connections
-        connections.retain(|_, val| !val.is_closed());
+        connections.retain(|_, pool| !pool.remove_closed(&mut tokenmanager));
     }
 
     tokenmanager.free_token(controltoken);
-    let _ = remove_file(QCM_CONTROL_SOCKET);
+    let _ = remove_file(&settings.socket);
 }
 
 
-fn accept_incoming<'a>(
+/// Accept new clients on the control socket, until there are no more pending
+/// connections. A client is served when it has sent HELLO and CONNECT, which
+/// may arrive in several reads.
+fn accept_incoming(
     listener: &UnixListener,
     tokenmanager: &mut TokenManager,
     poll: &mut Poll,
-    connections: &'a mut HashMap<String, Connection>,
+    new_clients: &mut HashMap<Token, NewClient>,
 ) {
-    let mut buf = [0; 2048];
-    let (mut socket,_) = listener.accept().unwrap();
-    let token = tokenmanager.allocate_token();
-
-    socket.read(&mut buf).unwrap();
-    let str = std::str::from_utf8(&buf).unwrap();
-
-    // TODO: Properly parse the CONN message in common function
-    let fields: Vec<&str> = str.split_whitespace().collect();
-    let conn = fields.get(0).unwrap();
-    if (*conn).ne("CONN") {
-        Client::send_socket_error(
-            &mut socket,
-            format!("Expected CONN message for incoming connection, got: {}", str).as_str()
-        );
-        socket.write("ERROR Expected CONN message".as_bytes()).unwrap();
-        return;
-    }
-    if fields.len() < 3 {
-        Client::send_socket_error(
-            &mut socket, format!("Malformed CONN message: {}", str).as_str());
-        return;
-    }
-    let address = fields.get(1).unwrap();
-    let app_proto = fields.get(2).unwrap();
-
-    if !connections.contains_key(&String::from(*address)) {
-        let conn = match Connection::new(address, app_proto, tokenmanager, poll) {
-            Ok(c) => c,
+    loop {
+        let mut socket = match listener.accept() {
+            Ok((socket, _)) => socket,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
             Err(e) => {
-                Client::send_socket_error(
-                    &mut socket, format!("Connection creation failed: {e}").as_str());
+                error!("Could not accept client: {}", e);
                 return;
-            }
+            },
         };
-        connections.insert(
-            String::from(*address), 
-            conn,
+        if let Err(e) = socket.set_nonblocking(true) {
+            Client::send_socket_error(&mut socket, &Error::Io(e));
+            continue;
+        }
+        let token = tokenmanager.allocate_token();
+        if let Err(e) = poll.registry()
+            .register(&mut SourceFd(&socket.as_raw_fd()), token, Interest::READABLE)
+        {
+            error!("Could not register client: {}", e);
+            tokenmanager.free_token(token);
+            continue;
+        }
+        new_clients.insert(token, NewClient::new(socket));
+    }
+}
+
+
+/// Continue HELLO and CONNECT exchange with new client that has become
+/// readable. When CONNECT is complete, the client is added to a connection to
+/// its destination, keeping its token.
+fn serve_new_client(
+    token: Token,
+    new_clients: &mut HashMap<Token, NewClient>,
+    tokenmanager: &mut TokenManager,
+    poll: &mut Poll,
+    connections: &mut HashMap<ConnectionKey, Pool>,
+    settings: &Settings,
+    sessions: &mut SessionCache,
+) {
+    let result = match new_clients.get_mut(&token).map(NewClient::read_connect) {
+        Some(Ok(Some(request))) => Ok(request),
+        Some(Err(e)) => Err(e),
+        Some(Ok(None)) | None => return,
+    };
+    let NewClient { mut socket, decoder, .. } = new_clients.remove(&token).unwrap();
+    poll.registry().deregister(&mut SourceFd(&socket.as_raw_fd())).unwrap();
+
+    let request = match result {
+        Ok(request) => request,
+        Err(e) => {
+            Client::send_socket_error(&mut socket, &e);
+            tokenmanager.free_token(token);
+            return;
+        },
+    };
+    match select_connection(&socket, &request, connections, settings, sessions, tokenmanager, poll) {
+        Ok(connection) => {
+            connection.add_client(socket, decoder, poll, token, request.early_data, tokenmanager);
+        },
+        Err(e) => {
+            Client::send_socket_error(&mut socket, &e);
+            tokenmanager.free_token(token);
+        },
+    }
+}
+
+
+/// Returns connection for client that has sent `request`, opening a new one
+/// if the pool of the destination has no suitable connection.
+fn select_connection<'a>(
+    socket: &UnixStream,
+    request: &ConnectRequest,
+    connections: &'a mut HashMap<ConnectionKey, Pool>,
+    settings: &Settings,
+    sessions: &mut SessionCache,
+    tokenmanager: &mut TokenManager,
+    poll: &mut Poll,
+) -> Result<&'a mut Connection, Error> {
+    let key = destination_key(socket, request, &settings.tls)?;
+    let congestion_control = congestion_control_hint(request)?;
+    let pool = connections.entry(key.clone()).or_insert_with(Pool::new);
+    let connection = pool.select_or_open(settings.policy, &settings.spill, || {
+        Connection::new(&key, settings, congestion_control, sessions.get(&key), tokenmanager, poll)
+    })?;
+    if let Some(cc) = congestion_control.filter(|cc| *cc != connection.congestion_control()) {
+        info!(
+            "Client asked for {} congestion control, but shares connection to {} using {}",
+            cc.name(), key.host(), connection.congestion_control().name()
         );
     }
-    let connection = connections.get_mut(&String::from(*address)).unwrap();
+    Ok(connection)
+}
 
-    connection.add_client(socket, app_proto, poll, token);
 
+/// Build connection key for `request`, with the client identity that the user
+/// on the other end of `socket` may use.
+fn destination_key(
+    socket: &UnixStream,
+    request: &ConnectRequest,
+    tls: &TlsSettings,
+) -> Result<ConnectionKey, Error> {
+    let mut key = ConnectionKey::new(request)?;
+    let cred = getsockopt(socket, PeerCredentials).map_err(std::io::Error::from)?;
+    let uid = Uid::from_raw(cred.uid());
+    key.identity = tls.select_identity(&key.host(), request.identity.as_deref(), uid)?;
+    Ok(key)
+}
+
+
+/// Congestion control algorithm that client requested for a new connection.
+fn congestion_control_hint(request: &ConnectRequest) -> Result<Option<CongestionControl>, Error> {
+    match request.congestion_control.as_deref() {
+        Some(name) => CongestionControl::from_name(name).map(Some).ok_or_else(|| {
+            Error::Protocol(format!("Unknown congestion control algorithm '{}'", name))
+        }),
+        None => Ok(None),
+    }
+}
+
+
+/// Client that has connected to the control socket, but not yet completed
+/// HELLO and CONNECT exchange. The socket is nonblocking, so the frames are
+/// collected from as many reads as they take.
+struct NewClient {
+    socket: UnixStream,
+    decoder: FrameDecoder,
+    /// Protocol version selected for the client, once HELLO is received.
+    version: Option<u16>,
+}
+
+impl NewClient {
+    fn new(socket: UnixStream) -> NewClient {
+        NewClient {
+            socket,
+            decoder: FrameDecoder::new(),
+            version: None,
+        }
+    }
+
+
+    /// Read bytes that client has sent, and answer HELLO with the selected
+    /// protocol version. Returns the request when CONNECT is complete, or None
+    /// if more bytes are needed. Bytes that follow CONNECT stay in the decoder.
+    fn read_connect(&mut self) -> Result<Option<ConnectRequest>, Error> {
+        let mut buf = [0; 2048];
+        loop {
+            while let Some(frame) = self.decoder.next_frame()? {
+                match (frame, self.version) {
+                    (Frame::Hello { version: offered }, None) => {
+                        let selected = negotiate_version(offered)?;
+                        debug!("Client offered version {}, using {}", offered, selected);
+                        // Socket buffer of a new client is empty, so the
+                        // reply fits in it.
+                        self.socket.write_all(&Frame::Hello { version: selected }.to_bytes())?;
+                        self.version = Some(selected);
+                    },
+                    (Frame::Connect {
+                        address, app_proto, server_name, identity, early_data, congestion_control,
+                    }, Some(_)) => {
+                        return Ok(Some(ConnectRequest {
+                            address, app_proto, server_name, identity, early_data,
+                            congestion_control,
+                        }));
+                    },
+                    (frame, _) => {
+                        return Err(Error::Protocol(
+                            format!("Expected HELLO and CONNECT, got: {:?}", frame)
+                        ));
+                    },
+                }
+            }
+            let n = match self.socket.read(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Io(e)),
+            };
+            if n == 0 {
+                return Err(Error::Protocol("Client closed socket before CONNECT".to_string()));
+            }
+            self.decoder.feed(&buf[..n]);
+        }
+    }
 }
@@ -0,0 +1,246 @@
+use std::time::Duration;
+
+use quic_cm::Error;
+
+use crate::{
+    connection::Connection,
+    mio_tokens::TokenManager,
+};
+
+
+/// How a client is given one of the connections to its destination.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PoolPolicy {
+    /// Use the oldest connection that can take more clients. Further
+    /// connections are opened only when earlier ones are saturated.
+    #[default]
+    First,
+    /// Use the connection with fewest streams.
+    LeastStreams,
+    /// Use the connections in turn.
+    RoundRobin,
+}
+
+impl PoolPolicy {
+    /// Policy by its name in configuration.
+    pub fn from_name(name: &str) -> Option<PoolPolicy> {
+        match name {
+            "first" => Some(PoolPolicy::First),
+            "least-streams" => Some(PoolPolicy::LeastStreams),
+            "round-robin" => Some(PoolPolicy::RoundRobin),
+            _ => None,
+        }
+    }
+}
+
+
+/// When new clients should get an additional connection to the destination,
+/// instead of waiting for the peer to allow more streams. Spilling is disabled
+/// when neither limit is set.
+#[derive(Default)]
+pub struct SpillPolicy {
+    /// Number of streams waiting to be opened.
+    pub queue_depth: Option<usize>,
+    /// Time that the oldest stream has waited to be opened.
+    pub max_wait: Option<Duration>,
+    /// Number of usable connections that are opened before clients share
+    /// them, so that least streams and round robin policies have connections
+    /// to spread clients on.
+    pub min_connections: usize,
+}
+
+
+/// State of a connection that selection of the connection depends on.
+struct Load {
+    usable: bool,
+    saturated: bool,
+    streams: usize,
+}
+
+/// Connections to one destination. New clients are given a connection that is
+/// usable and not saturated, chosen by the pool policy. A new connection is
+/// needed when there is no such connection.
+pub struct Pool {
+    connections: Vec<Connection>,
+    /// Position of the connection that round robin policy picks next.
+    next: usize,
+}
+
+impl Pool {
+    pub fn new() -> Pool {
+        Pool {
+            connections: Vec::new(),
+            next: 0,
+        }
+    }
+
+
+    /// Select connection for new client, or open a new one with `open` if
+    /// there is no suitable connection.
+    pub fn select_or_open(
+        &mut self,
+        policy: PoolPolicy,
+        spill: &SpillPolicy,
+        open: impl FnOnce() -> Result<Connection, Error>,
+    ) -> Result<&mut Connection, Error> {
+        if let Some(index) = self.select(policy, spill) {
+            return Ok(&mut self.connections[index]);
+        }
+        if !self.connections.is_empty() {
+            info!("No usable connection in pool, opening another");
+        }
+        self.connections.push(open()?);
+        Ok(self.connections.last_mut().unwrap())
+    }
+
+
+    /// Returns position of connection that `policy` selects, or None if a
+    /// new connection is needed.
+    fn select(&mut self, policy: PoolPolicy, spill: &SpillPolicy) -> Option<usize> {
+        let loads: Vec<Load> = self.connections.iter()
+            .map(|conn| Load {
+                usable: conn.is_usable(),
+                saturated: conn.is_saturated(spill),
+                streams: conn.num_streams(),
+            })
+            .collect();
+        choose(&loads, policy, spill.min_connections, &mut self.next)
+    }
+
+
+    pub fn iter(&self) -> impl Iterator<Item = &Connection> {
+        self.connections.iter()
+    }
+
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Connection> {
+        self.connections.iter_mut()
+    }
+
+
+    /// Drop connections that have been closed, and free their tokens. Returns
+    /// true if the pool became empty.
+    pub fn remove_closed(&mut self, tokenmanager: &mut TokenManager) -> bool {
+        self.connections.retain(|conn| {
+            if conn.is_closed() {
+                conn.cleanup(tokenmanager);
+            }
+            !conn.is_closed()
+        });
+        self.connections.is_empty()
+    }
+}
+
+
+/// Returns position of the connection in `loads` that `policy` selects, or
+/// None if there are fewer than `min_connections` usable connections, or none
+/// of them can take new clients. `next` is the position that round robin
+/// policy picks next.
+fn choose(
+    loads: &[Load],
+    policy: PoolPolicy,
+    min_connections: usize,
+    next: &mut usize,
+) -> Option<usize> {
+    if loads.iter().filter(|load| load.usable).count() < min_connections {
+        return None;
+    }
+    let candidates: Vec<usize> = (0..loads.len())
+        .filter(|i| loads[*i].usable && !loads[*i].saturated)
+        .collect();
+    match policy {
+        PoolPolicy::First => candidates.first().copied(),
+        PoolPolicy::LeastStreams => candidates.iter()
+            .min_by_key(|i| loads[**i].streams)
+            .copied(),
+        PoolPolicy::RoundRobin => {
+            let index = candidates.iter()
+                .find(|i| **i >= *next)
+                .or(candidates.first())
+                .copied();
+            if let Some(i) = index {
+                *next = i + 1;
+            }
+            index
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(streams: usize) -> Load {
+        Load { usable: true, saturated: false, streams }
+    }
+
+
+    fn saturated(streams: usize) -> Load {
+        Load { saturated: true, ..load(streams) }
+    }
+
+
+    fn unusable(streams: usize) -> Load {
+        Load { usable: false, ..load(streams) }
+    }
+
+
+    /// Positions that `policy` selects for `rounds` clients in a row.
+    fn choices(loads: &[Load], policy: PoolPolicy, rounds: usize) -> Vec<Option<usize>> {
+        let mut next = 0;
+        (0..rounds).map(|_| choose(loads, policy, 1, &mut next)).collect()
+    }
+
+
+    #[test]
+    fn test_first() {
+        let loads = [unusable(0), saturated(1), load(5), load(0)];
+        assert_eq!(choices(&loads, PoolPolicy::First, 3), [Some(2); 3]);
+    }
+
+
+    #[test]
+    fn test_least_streams() {
+        let loads = [load(3), saturated(0), load(1), unusable(0), load(2)];
+        assert_eq!(choices(&loads, PoolPolicy::LeastStreams, 2), [Some(2); 2]);
+        // Tie goes to the older connection
+        let loads = [load(2), load(1), load(1)];
+        assert_eq!(choices(&loads, PoolPolicy::LeastStreams, 1), [Some(1)]);
+    }
+
+
+    #[test]
+    fn test_round_robin() {
+        let loads = [load(0), saturated(0), load(0), unusable(0), load(0)];
+        assert_eq!(
+            choices(&loads, PoolPolicy::RoundRobin, 5),
+            [Some(0), Some(2), Some(4), Some(0), Some(2)]
+        );
+    }
+
+
+    #[test]
+    fn test_no_candidates() {
+        let loads = [saturated(0), unusable(0)];
+        for policy in [PoolPolicy::First, PoolPolicy::LeastStreams, PoolPolicy::RoundRobin] {
+            assert_eq!(choices(&loads, policy, 1), [None]);
+            assert_eq!(choices(&[], policy, 1), [None]);
+        }
+    }
+
+
+    #[test]
+    fn test_min_connections() {
+        let mut next = 0;
+        for policy in [PoolPolicy::First, PoolPolicy::LeastStreams, PoolPolicy::RoundRobin] {
+            assert_eq!(choose(&[load(0), load(0)], policy, 3, &mut next), None);
+            // Connections that cannot take clients do not count
+            assert_eq!(choose(&[load(0), load(0), unusable(0)], policy, 3, &mut next), None);
+            // Saturated connections count, but are not selected
+            assert!(choose(&[load(0), load(0), saturated(0)], policy, 3, &mut next)
+                .is_some_and(|i| i < 2));
+        }
+    }
+}
+
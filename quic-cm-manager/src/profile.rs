@@ -0,0 +1,209 @@
+use std::net::IpAddr;
+
+use crate::{
+    config::{ProfileConfig, TransportOverrides},
+    destination::ConnectionKey,
+};
+
+
+/// Transport parameters for a group of destinations, for example long
+/// distance links or local services. Destination matches the profile when it
+/// matches all criteria that the profile has: one of its host patterns, one of
+/// its networks, and one of its application protocols.
+///
+/// Profiles are not merged. When several profiles match a destination, only
+/// the first one in the configuration file is used, and settings of the
+/// destination override it.
+pub struct Profile {
+    pub name: String,
+    /// Server names or IP addresses. Pattern `*.example.com` matches any name
+    /// under `example.com`.
+    hosts: Vec<String>,
+    networks: Vec<Network>,
+    alpn: Vec<String>,
+    pub transport: TransportOverrides,
+}
+
+
+/// IP network in CIDR notation.
+struct Network {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+
+impl Profile {
+    pub fn from_config(config: &ProfileConfig) -> Result<Profile, String> {
+        let networks = config.networks.iter()
+            .map(|n| Network::parse(n))
+            .collect::<Result<Vec<Network>, String>>()
+            .map_err(|e| format!("Profile '{}': {}", config.name, e))?;
+        Ok(Profile {
+            name: config.name.clone(),
+            hosts: config.hosts.iter()
+                .map(|h| h.trim_end_matches('.').to_ascii_lowercase())
+                .collect(),
+            networks,
+            alpn: config.alpn.clone(),
+            transport: config.transport.clone(),
+        })
+    }
+
+
+    pub fn matches(&self, key: &ConnectionKey) -> bool {
+        let host = key.host();
+        let ip = key.peer.ip();
+        (self.hosts.is_empty() || self.hosts.iter().any(|p| host_matches(p, &host)))
+            && (self.networks.is_empty() || self.networks.iter().any(|n| n.contains(ip)))
+            && (self.alpn.is_empty() || self.alpn.contains(&key.app_proto))
+    }
+}
+
+
+impl Network {
+    /// Parse `<address>/<prefix length>`. Address without prefix length is a
+    /// network of one address.
+    fn parse(s: &str) -> Result<Network, String> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("Invalid network '{}'", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse().ok()
+                .filter(|len| *len <= max_len)
+                .ok_or(format!("Invalid prefix length in '{}'", s))?,
+            None => max_len,
+        };
+        Ok(Network { addr, prefix_len })
+    }
+
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix_len)
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(u128::from(net), u128::from(ip), 128, self.prefix_len)
+            },
+            _ => false,
+        }
+    }
+}
+
+
+/// Returns true if first `prefix_len` bits of `bits`-bit addresses `a` and `b`
+/// are equal.
+fn prefix_eq(a: u128, b: u128, bits: u32, prefix_len: u32) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len;
+    (a >> shift) == (b >> shift)
+}
+
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(server_name: Option<&str>, ip: &str, app_proto: &str) -> ConnectionKey {
+        ConnectionKey {
+            peer: (ip.parse::<IpAddr>().unwrap(), 443).into(),
+            app_proto: app_proto.to_string(),
+            server_name: server_name.map(str::to_string),
+            identity: None,
+        }
+    }
+
+
+    fn new_profile(hosts: &[&str], networks: &[&str], alpn: &[&str]) -> Profile {
+        Profile::from_config(&ProfileConfig {
+            name: "test".to_string(),
+            hosts: hosts.iter().map(|h| h.to_string()).collect(),
+            networks: networks.iter().map(|n| n.to_string()).collect(),
+            alpn: alpn.iter().map(|a| a.to_string()).collect(),
+            transport: TransportOverrides::default(),
+        }).unwrap()
+    }
+
+
+    #[test]
+    fn test_host_matches() {
+        let cases = [
+            ("*.example.com", "a.example.com", true),
+            ("*.example.com", "a.b.example.com", true),
+            ("*.example.com", "example.com", false),
+            ("*.example.com", "xexample.com", false),
+            ("*.example.com", "a.xexample.com", false),
+            ("*.example.com", "example.com.evil", false),
+            ("example.com", "example.com", true),
+            ("example.com", "a.example.com", false),
+            ("192.0.2.1", "192.0.2.1", true),
+        ];
+        for (pattern, host, expected) in cases {
+            assert_eq!(host_matches(pattern, host), expected, "{} {}", pattern, host);
+        }
+        // Patterns are normalized like server names
+        let profile = new_profile(&["*.Example.COM."], &[], &[]);
+        assert!(profile.matches(&key(Some("a.example.com"), "192.0.2.1", "h3")));
+    }
+
+
+    #[test]
+    fn test_network_contains() {
+        let cases = [
+            ("0.0.0.0/0", "203.0.113.9", true),
+            ("0.0.0.0/0", "::1", false),
+            ("192.0.2.1/32", "192.0.2.1", true),
+            ("192.0.2.1/32", "192.0.2.0", false),
+            ("192.0.2.1", "192.0.2.1", true),
+            ("192.0.2.1", "192.0.2.2", false),
+            ("192.0.2.0/24", "192.0.2.255", true),
+            ("192.0.2.0/24", "192.0.3.0", false),
+            // Host bits of the address are ignored
+            ("192.0.2.77/24", "192.0.2.1", true),
+            ("192.0.2.77/24", "192.0.1.255", false),
+            ("10.0.0.0/13", "10.7.255.255", true),
+            ("10.0.0.0/13", "10.8.0.0", false),
+            ("::/0", "2001:db8::1", true),
+            ("::/0", "192.0.2.1", false),
+            ("2001:db8::/32", "2001:db8:ffff::1", true),
+            ("2001:db8::/32", "2001:db9::1", false),
+            ("2001:db8::1/128", "2001:db8::1", true),
+            ("2001:db8::1/128", "2001:db8::2", false),
+        ];
+        for (network, ip, expected) in cases {
+            let contains = Network::parse(network).unwrap().contains(ip.parse().unwrap());
+            assert_eq!(contains, expected, "{} {}", network, ip);
+        }
+        for invalid in ["192.0.2.0/33", "2001:db8::/129", "192.0.2.0/", "192.0.2/24", "x/8"] {
+            assert!(Network::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+
+    #[test]
+    fn test_profile_criteria() {
+        let profile = new_profile(&["*.example.com"], &["192.0.2.0/24"], &["h3"]);
+        assert!(profile.matches(&key(Some("a.example.com"), "192.0.2.1", "h3")));
+        assert!(!profile.matches(&key(Some("a.example.org"), "192.0.2.1", "h3")));
+        assert!(!profile.matches(&key(Some("a.example.com"), "198.51.100.1", "h3")));
+        assert!(!profile.matches(&key(Some("a.example.com"), "192.0.2.1", "hq")));
+        // Destination given by IP address is matched by the address
+        let profile = new_profile(&["192.0.2.1"], &[], &[]);
+        assert!(profile.matches(&key(None, "192.0.2.1", "h3")));
+        assert!(!profile.matches(&key(Some("example.com"), "192.0.2.1", "h3")));
+        assert!(new_profile(&[], &[], &[]).matches(&key(None, "::1", "h3")));
+    }
+}
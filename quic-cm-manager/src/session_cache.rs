@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    fs::{self, DirBuilder, OpenOptions},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::PathBuf,
+};
+
+use ring::digest::{SHA256, digest};
+
+use crate::destination::ConnectionKey;
+
+
+/// TLS session tickets of destinations, so that new connections can resume
+/// earlier sessions without full handshake. Sessions are stored per connection
+/// key, so that they are not resumed with different server name or client
+/// identity.
+pub struct SessionCache {
+    sessions: HashMap<ConnectionKey, Vec<u8>>,
+    /// Directory where sessions are also stored, so that they survive manager
+    /// restarts.
+    dir: Option<PathBuf>,
+}
+
+impl SessionCache {
+    /// Create cache that also stores sessions in `dir`, if given.
+    pub fn new(dir: Option<PathBuf>) -> SessionCache {
+        SessionCache {
+            sessions: HashMap::new(),
+            dir,
+        }
+    }
+
+
+    /// Returns stored session of destination `key`.
+    pub fn get(&mut self, key: &ConnectionKey) -> Option<&[u8]> {
+        if !self.sessions.contains_key(key) {
+            let path = self.path(key)?;
+            let session = fs::read(path).ok()?;
+            self.sessions.insert(key.clone(), session);
+        }
+        self.sessions.get(key).map(Vec::as_slice)
+    }
+
+
+    /// Store `session` of destination `key`, replacing the earlier one.
+    pub fn store(&mut self, key: &ConnectionKey, session: Vec<u8>) {
+        if let Some(path) = self.path(key) {
+            if let Err(e) = write_private(&path, &session) {
+                warn!("Could not store session to {}: {}", path.display(), e);
+            }
+        }
+        self.sessions.insert(key.clone(), session);
+    }
+
+
+    /// File of the session of `key` in cache directory. Name is a hash of the
+    /// key, so that it is valid file name whatever the server name is.
+    fn path(&self, key: &ConnectionKey) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        let id = format!(
+            "{}\n{}\n{}\n{}",
+            key.peer,
+            key.app_proto,
+            key.server_name.as_deref().unwrap_or(""),
+            key.identity.as_deref().unwrap_or(""),
+        );
+        let hash: String = digest(&SHA256, id.as_bytes()).as_ref().iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        Some(dir.join(hash))
+    }
+}
+
+
+/// Write `data` to `path` readable only by the owner, replacing the file
+/// atomically. Session tickets carry keys of the session.
+fn write_private(path: &PathBuf, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(data)?;
+    fs::rename(&tmp, path)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, process};
+
+    use super::*;
+
+    /// Empty directory for test `name`, under the system temporary directory.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("quic-cm-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+
+    fn key(identity: Option<&str>) -> ConnectionKey {
+        ConnectionKey {
+            peer: "127.0.0.1:7878".parse().unwrap(),
+            app_proto: "test".to_string(),
+            server_name: Some("quic.tech".to_string()),
+            identity: identity.map(str::to_string),
+        }
+    }
+
+
+    #[test]
+    fn test_store_get() {
+        let mut cache = SessionCache::new(None);
+        assert_eq!(cache.get(&key(None)), None);
+        cache.store(&key(None), b"session".to_vec());
+        assert_eq!(cache.get(&key(None)), Some(&b"session"[..]));
+        // Sessions are not shared between identities
+        assert_eq!(cache.get(&key(Some("client"))), None);
+        cache.store(&key(None), b"newer".to_vec());
+        assert_eq!(cache.get(&key(None)), Some(&b"newer"[..]));
+    }
+
+
+    #[test]
+    fn test_reload() {
+        let dir = test_dir("session-reload");
+        let mut cache = SessionCache::new(Some(dir.clone()));
+        cache.store(&key(None), b"session".to_vec());
+        cache.store(&key(Some("client")), b"client session".to_vec());
+
+        let mut cache = SessionCache::new(Some(dir.clone()));
+        assert_eq!(cache.get(&key(None)), Some(&b"session"[..]));
+        assert_eq!(cache.get(&key(Some("client"))), Some(&b"client session"[..]));
+        assert_eq!(cache.get(&key(Some("other"))), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn test_write_private() {
+        let dir = test_dir("session-private");
+        let path = dir.join("session");
+        write_private(&path, b"session").unwrap();
+        let mode = |path: &PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(&dir), 0o700);
+        // Replacing keeps the mode, and leaves no temporary file
+        write_private(&path, b"newer").unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(fs::read(&path).unwrap(), b"newer");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,595 @@
+use std::{collections::HashMap, net::IpAddr, path::Path};
+
+use nix::unistd::{Uid, User, getuid};
+use quiche::Config;
+use quic_cm::Error;
+use ring::digest::{SHA256, digest};
+
+use crate::config::{DestinationConfig, IdentityConfig, TlsConfig};
+
+
+/// TLS alert that is used when peer certificate does not match its pins.
+const ALERT_BAD_CERTIFICATE: u64 = 42;
+
+/// Object identifier of the subject alternative name extension, in DER.
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+
+/// How server certificates are verified. By default certificates are verified
+/// against the system CA bundle.
+pub struct TlsSettings {
+    /// Verify the server certificate chain and name.
+    pub verify: bool,
+    /// File of trusted CA certificates in PEM format, in addition to the
+    /// system CA bundle.
+    pub ca_file: Option<String>,
+    /// Directory of trusted CA certificates.
+    pub ca_dir: Option<String>,
+    /// Accepted certificates of destinations, key is server name or IP
+    /// address. Server certificate must match one of the pins, in addition
+    /// to passing verification.
+    pub pins: HashMap<String, Vec<Pin>>,
+    /// Client certificates by name.
+    pub identities: HashMap<String, Identity>,
+    /// Identity used for destination when client does not request one. Key is
+    /// server name or IP address.
+    pub default_identities: HashMap<String, String>,
+}
+
+
+/// Client certificate that manager presents to servers on behalf of local
+/// users.
+pub struct Identity {
+    /// PEM file of the certificate chain.
+    pub cert_chain: String,
+    /// PEM file of the private key.
+    pub key: String,
+    /// Local users that may use the identity. None allows all users.
+    pub users: Option<Vec<Uid>>,
+}
+
+
+/// SHA-256 hash of the server certificate, or its public key.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pin {
+    /// Hash of the DER encoded certificate.
+    Cert([u8; 32]),
+    /// Hash of the DER encoded SubjectPublicKeyInfo of the certificate, that
+    /// stays the same when certificate is renewed with the same key.
+    Spki([u8; 32]),
+}
+
+
+impl TlsSettings {
+    /// Build settings from `[tls]` section of configuration file, and pins and
+    /// default identities of destinations. Identity without users may only be
+    /// used by the user running the manager.
+    pub fn from_config(
+        config: &TlsConfig,
+        destinations: &[DestinationConfig],
+    ) -> Result<TlsSettings, String> {
+        let mut identities = HashMap::new();
+        for (name, identity) in &config.identities {
+            identities.insert(name.clone(), Identity::from_config(identity)?);
+        }
+        let mut pins = HashMap::new();
+        let mut default_identities = HashMap::new();
+        for dest in destinations {
+            let host = dest.host.trim_end_matches('.').to_ascii_lowercase();
+            if !dest.pins.is_empty() {
+                let list = dest.pins.iter()
+                    .map(|pin| Pin::parse(pin))
+                    .collect::<Result<Vec<Pin>, String>>()?;
+                pins.insert(host.clone(), list);
+            }
+            if let Some(name) = &dest.identity {
+                default_identities.insert(host, name.clone());
+            }
+        }
+        let settings = TlsSettings {
+            verify: config.verify.unwrap_or(true),
+            ca_file: config.ca_file.clone(),
+            ca_dir: config.ca_dir.clone(),
+            pins,
+            identities,
+            default_identities,
+        };
+        settings.validate()?;
+        Ok(settings)
+    }
+
+
+    /// Check that CA locations and identity files exist, so that mistakes are
+    /// noticed at startup rather than on first connection.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(file) = &self.ca_file {
+            if !Path::new(file).is_file() {
+                return Err(format!("CA file '{}' not found", file));
+            }
+        }
+        if let Some(dir) = &self.ca_dir {
+            if !Path::new(dir).is_dir() {
+                return Err(format!("CA directory '{}' not found", dir));
+            }
+        }
+        for (name, identity) in &self.identities {
+            for file in [&identity.cert_chain, &identity.key] {
+                if !Path::new(file).is_file() {
+                    return Err(format!("File '{}' of identity '{}' not found", file, name));
+                }
+            }
+        }
+        for (host, name) in &self.default_identities {
+            if !self.identities.contains_key(name) {
+                return Err(format!("Unknown identity '{}' for destination '{}'", name, host));
+            }
+        }
+        Ok(())
+    }
+
+
+    /// Apply verification settings, and certificate of client `identity`, to
+    /// QUIC configuration. System CA bundle is loaded by quiche when the
+    /// configuration is created.
+    pub fn configure(&self, config: &mut Config, identity: Option<&str>) -> Result<(), Error> {
+        config.verify_peer(self.verify);
+        if let Some(file) = &self.ca_file {
+            config.load_verify_locations_from_file(file).map_err(|e| {
+                Error::TlsVerification(format!("Could not load CA file '{}': {:?}", file, e))
+            })?;
+        }
+        if let Some(dir) = &self.ca_dir {
+            config.load_verify_locations_from_directory(dir).map_err(|e| {
+                Error::TlsVerification(format!("Could not load CA directory '{}': {:?}", dir, e))
+            })?;
+        }
+        if let Some(name) = identity {
+            let identity = self.identities.get(name).ok_or_else(|| {
+                Error::PermissionDenied(format!("Unknown identity '{}'", name))
+            })?;
+            config.load_cert_chain_from_pem_file(&identity.cert_chain).map_err(|e| {
+                Error::Handshake(format!("Could not load certificate of '{}': {:?}", name, e))
+            })?;
+            config.load_priv_key_from_pem_file(&identity.key).map_err(|e| {
+                Error::Handshake(format!("Could not load key of '{}': {:?}", name, e))
+            })?;
+        }
+        Ok(())
+    }
+
+
+    /// Select client identity for user `uid` connecting to `host`: `requested`
+    /// by the client, or the default identity of the destination. Fails if the
+    /// user may not use the identity.
+    pub fn select_identity(
+        &self,
+        host: &str,
+        requested: Option<&str>,
+        uid: Uid,
+    ) -> Result<Option<String>, Error> {
+        let name = match requested.or(self.default_identities.get(host).map(String::as_str)) {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let identity = self.identities.get(name).ok_or_else(|| {
+            Error::PermissionDenied(format!("Unknown identity '{}'", name))
+        })?;
+        if !identity.users.as_ref().is_none_or(|users| users.contains(&uid)) {
+            return Err(Error::PermissionDenied(
+                format!("User {} may not use identity '{}'", uid, name)
+            ));
+        }
+        Ok(Some(name.to_string()))
+    }
+
+
+    /// Returns pins of destination `host`, server name or IP address.
+    pub fn pins_for(&self, host: &str) -> Vec<Pin> {
+        self.pins.get(host).cloned().unwrap_or_default()
+    }
+
+
+    /// Returns true if server certificate must be issued for the IP address of
+    /// destination. TLS verifies only server names, so without one or pins
+    /// any trusted certificate would be accepted.
+    pub fn checks_address(&self, server_name: Option<&str>, host: &str) -> bool {
+        self.verify && server_name.is_none() && !self.pins.contains_key(host)
+    }
+}
+
+
+impl Identity {
+    fn from_config(config: &IdentityConfig) -> Result<Identity, String> {
+        let mut users = Vec::new();
+        for user in &config.users {
+            if user == "*" {
+                users.clear();
+                break;
+            }
+            users.push(parse_user(user)?);
+        }
+        let users = match (config.users.is_empty(), users.is_empty()) {
+            (true, _) => Some(vec![getuid()]),
+            // "*" was given
+            (false, true) => None,
+            (false, false) => Some(users),
+        };
+        Ok(Identity {
+            cert_chain: config.cert_chain.clone(),
+            key: config.key.clone(),
+            users,
+        })
+    }
+}
+
+
+impl Pin {
+    fn parse(s: &str) -> Result<Pin, String> {
+        let (kind, hex) = s.trim().split_once(':').ok_or(format!("Invalid pin '{}'", s))?;
+        let hash = parse_hash(hex).ok_or(format!("Invalid SHA-256 hash in pin '{}'", s))?;
+        match kind {
+            "cert-sha256" => Ok(Pin::Cert(hash)),
+            "spki-sha256" => Ok(Pin::Spki(hash)),
+            _ => Err(format!("Unknown pin type '{}'", kind)),
+        }
+    }
+
+
+    fn matches(&self, cert: &[u8]) -> bool {
+        match self {
+            Pin::Cert(hash) => digest(&SHA256, cert).as_ref() == hash,
+            Pin::Spki(hash) => subject_public_key_info(cert)
+                .is_some_and(|spki| digest(&SHA256, spki).as_ref() == hash),
+        }
+    }
+}
+
+
+/// Check DER encoded server certificate against `pins`. Any certificate is
+/// accepted if there are no pins.
+pub fn check_pins(pins: &[Pin], cert: Option<&[u8]>) -> Result<(), Error> {
+    if pins.is_empty() {
+        return Ok(());
+    }
+    match cert {
+        Some(cert) if pins.iter().any(|pin| pin.matches(cert)) => Ok(()),
+        Some(_) => Err(Error::TlsVerification("Certificate does not match pins".to_string())),
+        None => Err(Error::TlsVerification("Server sent no certificate".to_string())),
+    }
+}
+
+
+/// Check that DER encoded server certificate has IP address `ip` in its
+/// subject alternative names.
+pub fn check_ip_address(ip: IpAddr, cert: Option<&[u8]>) -> Result<(), Error> {
+    match cert {
+        Some(cert) if ip_addresses(cert).contains(&ip) => Ok(()),
+        Some(_) => Err(Error::TlsVerification(format!("Certificate is not issued for {}", ip))),
+        None => Err(Error::TlsVerification("Server sent no certificate".to_string())),
+    }
+}
+
+
+/// Connection close error code for TLS alert, so that the close is reported
+/// as verification failure.
+pub fn crypto_error_code() -> u64 {
+    0x100 + ALERT_BAD_CERTIFICATE
+}
+
+
+/// Returns user ID from user name or number.
+fn parse_user(user: &str) -> Result<Uid, String> {
+    if let Ok(uid) = user.parse() {
+        return Ok(Uid::from_raw(uid));
+    }
+    match User::from_name(user) {
+        Ok(Some(u)) => Ok(u.uid),
+        Ok(None) => Err(format!("Unknown user '{}'", user)),
+        Err(e) => Err(format!("Could not look up user '{}': {}", user, e)),
+    }
+}
+
+
+fn parse_hash(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (i, b) in hash.iter_mut().enumerate() {
+        *b = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+
+/// Split DER element from start of `buf`. Returns the tag, the content, and
+/// the length of the whole element.
+fn der_element(buf: &[u8]) -> Option<(u8, &[u8], usize)> {
+    let tag = *buf.first()?;
+    let first = *buf.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = buf.get(2..2 + n)?.iter().fold(0, |len, b| (len << 8) | *b as usize);
+        (len, 2 + n)
+    };
+    let end = header.checked_add(len)?;
+    Some((tag, buf.get(header..end)?, end))
+}
+
+
+/// Returns the fields of TBSCertificate of X.509 certificate that follow the
+/// subject, starting with SubjectPublicKeyInfo.
+fn after_subject(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _) = der_element(cert)?;
+    let (_, mut tbs, _) = der_element(cert)?;
+    // Optional explicit version
+    if tbs.first() == Some(&0xa0) {
+        tbs = &tbs[der_element(tbs)?.2..];
+    }
+    // Serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        tbs = &tbs[der_element(tbs)?.2..];
+    }
+    Some(tbs)
+}
+
+
+/// Returns DER encoded SubjectPublicKeyInfo of X.509 certificate.
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let tbs = after_subject(cert)?;
+    let (_, _, len) = der_element(tbs)?;
+    Some(&tbs[..len])
+}
+
+
+/// Returns the content of GeneralNames in subject alternative name extension
+/// of X.509 certificate.
+fn subject_alt_names(cert: &[u8]) -> Option<&[u8]> {
+    let mut tbs = after_subject(cert)?;
+    // Extensions follow SubjectPublicKeyInfo and optional unique IDs
+    let mut extensions = loop {
+        let (tag, content, len) = der_element(tbs)?;
+        if tag == 0xa3 {
+            break der_element(content)?.1;
+        }
+        tbs = &tbs[len..];
+    };
+    while !extensions.is_empty() {
+        let (_, extension, len) = der_element(extensions)?;
+        extensions = &extensions[len..];
+        let (_, oid, oid_len) = der_element(extension)?;
+        if oid != SUBJECT_ALT_NAME {
+            continue;
+        }
+        let mut value = &extension[oid_len..];
+        // Optional critical flag
+        if value.first() == Some(&0x01) {
+            value = &value[der_element(value)?.2..];
+        }
+        let (_, names, _) = der_element(der_element(value)?.1)?;
+        return Some(names);
+    }
+    None
+}
+
+
+/// Returns IP addresses in subject alternative names of X.509 certificate.
+fn ip_addresses(cert: &[u8]) -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+    let mut names = subject_alt_names(cert).unwrap_or_default();
+    while let Some((tag, content, len)) = der_element(names) {
+        // iPAddress is context specific tag 7
+        if tag == 0x87 {
+            if let Ok(ip) = <[u8; 4]>::try_from(content) {
+                addrs.push(IpAddr::from(ip));
+            } else if let Ok(ip) = <[u8; 16]>::try_from(content) {
+                addrs.push(IpAddr::from(ip));
+            }
+        }
+        names = &names[len..];
+    }
+    addrs
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Certificate of the test server in quic-cm-lib/tests, in DER.
+    const CERT: &[u8] = include_bytes!("../testdata/cert.der");
+
+    /// Certificate issued by testdata/ca.crt for 127.0.0.1 and ::1, in DER.
+    const IP_CERT: &[u8] = include_bytes!("../testdata/ip.der");
+
+    /// SHA-256 hashes of CERT and its SubjectPublicKeyInfo, computed with
+    /// openssl.
+    const CERT_HASH: &str = "3e7b7dd003758ae1d66932d3a3ee57d24b1113f35ff63f915ddff6e83a0ad209";
+    const SPKI_HASH: &str = "2f31194864bb64346aeaccf9153de8609a15b63fe30d60c1fdbe787d804c182b";
+
+    const OTHER_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+
+    fn pins(pins: &[&str]) -> Vec<Pin> {
+        pins.iter().map(|pin| Pin::parse(pin).unwrap()).collect()
+    }
+
+
+    #[test]
+    fn test_pin_match() {
+        let cert_pin = format!("cert-sha256:{}", CERT_HASH);
+        let spki_pin = format!("spki-sha256:{}", SPKI_HASH);
+        let other_cert = format!("cert-sha256:{}", OTHER_HASH);
+        let other_spki = format!("spki-sha256:{}", OTHER_HASH);
+        // Hash of the public key is not a certificate pin, and vice versa
+        let swapped_cert = format!("cert-sha256:{}", SPKI_HASH);
+        let swapped_spki = format!("spki-sha256:{}", CERT_HASH);
+
+        assert!(check_pins(&[], Some(CERT)).is_ok());
+        assert!(check_pins(&[], None).is_ok());
+        assert!(check_pins(&pins(&[&cert_pin]), Some(CERT)).is_ok());
+        assert!(check_pins(&pins(&[&spki_pin]), Some(CERT)).is_ok());
+        assert!(check_pins(&pins(&[&other_cert, &spki_pin]), Some(CERT)).is_ok());
+        for mismatch in [&other_cert, &other_spki, &swapped_cert, &swapped_spki] {
+            assert!(matches!(
+                check_pins(&pins(&[mismatch]), Some(CERT)),
+                Err(Error::TlsVerification(_))
+            ));
+        }
+        assert!(matches!(check_pins(&pins(&[&cert_pin]), None), Err(Error::TlsVerification(_))));
+    }
+
+
+    #[test]
+    fn test_subject_public_key_info() {
+        let spki = subject_public_key_info(CERT).unwrap();
+        assert_eq!(digest(&SHA256, spki).as_ref(), parse_hash(SPKI_HASH).unwrap());
+    }
+
+
+    #[test]
+    fn test_malformed_certificate() {
+        let spki = pins(&[&format!("spki-sha256:{}", SPKI_HASH)]);
+        let mut huge_length = CERT.to_vec();
+        // Outer SEQUENCE claims 2^32-1 bytes
+        huge_length.splice(1..4, [0x84, 0xff, 0xff, 0xff, 0xff]);
+        let mut indefinite_length = CERT.to_vec();
+        indefinite_length[1] = 0x80;
+        let malformed: [&[u8]; 7] = [
+            &[],
+            &[0x30],
+            &[0x30, 0x05, 0x30],
+            &CERT[..CERT.len() / 2],
+            &CERT[..CERT.len() - 1],
+            &huge_length,
+            &indefinite_length,
+        ];
+        for cert in malformed {
+            assert_eq!(subject_public_key_info(cert), None);
+            assert!(check_pins(&spki, Some(cert)).is_err());
+            assert!(ip_addresses(cert).is_empty());
+        }
+    }
+
+
+    #[test]
+    fn test_ip_addresses() {
+        let v4 = IpAddr::from([127, 0, 0, 1]);
+        let v6 = IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1u16]);
+        assert_eq!(ip_addresses(IP_CERT), [v4, v6]);
+        assert!(check_ip_address(v4, Some(IP_CERT)).is_ok());
+        assert!(check_ip_address(v6, Some(IP_CERT)).is_ok());
+        let other = IpAddr::from([127, 0, 0, 2]);
+        assert!(matches!(check_ip_address(other, Some(IP_CERT)), Err(Error::TlsVerification(_))));
+        assert!(matches!(check_ip_address(v4, None), Err(Error::TlsVerification(_))));
+        // Version 1 certificate without extensions
+        assert!(ip_addresses(CERT).is_empty());
+        assert!(check_ip_address(v4, Some(CERT)).is_err());
+    }
+
+
+    #[test]
+    fn test_der_element() {
+        assert_eq!(der_element(&[0x02, 0x01, 0x07, 0xff]), Some((0x02, &[0x07][..], 3)));
+        let mut long = vec![0x04, 0x81, 0x80];
+        long.extend_from_slice(&[0; 0x80]);
+        assert_eq!(der_element(&long), Some((0x04, &[0; 0x80][..], 0x83)));
+        assert_eq!(der_element(&[0x04, 0x85, 1, 0, 0, 0, 0]), None);
+        assert_eq!(der_element(&[0x04, 0x02, 0x00]), None);
+    }
+
+
+    #[test]
+    fn test_parse_pin() {
+        assert_eq!(
+            Pin::parse(&format!(" cert-sha256:{} ", CERT_HASH)),
+            Ok(Pin::Cert(parse_hash(CERT_HASH).unwrap()))
+        );
+        assert_eq!(
+            Pin::parse(&format!("spki-sha256:{}", SPKI_HASH.to_uppercase())),
+            Ok(Pin::Spki(parse_hash(SPKI_HASH).unwrap()))
+        );
+        for invalid in [
+            "".to_string(),
+            CERT_HASH.to_string(),
+            format!("sha1:{}", CERT_HASH),
+            format!("cert-sha256:{}", &CERT_HASH[1..]),
+            format!("cert-sha256:{}0", CERT_HASH),
+            format!("cert-sha256:{}", CERT_HASH.replace('3', "g")),
+        ] {
+            assert!(Pin::parse(&invalid).is_err(), "{}", invalid);
+        }
+    }
+
+
+    fn identity(users: &[&str]) -> Identity {
+        let config = IdentityConfig {
+            cert_chain: "client.crt".to_string(),
+            key: "client.key".to_string(),
+            users: users.iter().map(|user| user.to_string()).collect(),
+        };
+        Identity::from_config(&config).unwrap()
+    }
+
+
+    #[test]
+    fn test_identity_users() {
+        assert_eq!(identity(&["*"]).users, None);
+        assert_eq!(identity(&["1000", "*"]).users, None);
+        // Without users only the manager's user may use the identity
+        assert_eq!(identity(&[]).users, Some(vec![getuid()]));
+        let listed = Some(vec![Uid::from_raw(0), Uid::from_raw(1000)]);
+        assert_eq!(identity(&["0", "1000"]).users, listed);
+        let unknown = IdentityConfig {
+            cert_chain: "client.crt".to_string(),
+            key: "client.key".to_string(),
+            users: vec!["no-such-user-quic-cm".to_string()],
+        };
+        assert!(Identity::from_config(&unknown).is_err());
+    }
+
+
+    #[test]
+    fn test_select_identity() {
+        let own = getuid();
+        let other = Uid::from_raw(own.as_raw() + 1);
+        let settings = TlsSettings {
+            verify: true,
+            ca_file: None,
+            ca_dir: None,
+            pins: HashMap::new(),
+            identities: HashMap::from([
+                ("all".to_string(), identity(&["*"])),
+                ("own".to_string(), identity(&[])),
+                ("other".to_string(), identity(&[&other.to_string()])),
+            ]),
+            default_identities: HashMap::from([
+                ("own.example".to_string(), "own".to_string()),
+                ("other.example".to_string(), "other".to_string()),
+            ]),
+        };
+        let select = |host, requested, uid| settings.select_identity(host, requested, uid);
+        let denied = |result| matches!(result, Err(Error::PermissionDenied(_)));
+
+        for uid in [own, other] {
+            assert_eq!(select("none.example", Some("all"), uid).unwrap().as_deref(), Some("all"));
+            assert_eq!(select("none.example", None, uid).unwrap(), None);
+            assert!(denied(select("none.example", Some("unknown"), uid)));
+        }
+        assert_eq!(select("none.example", Some("own"), own).unwrap().as_deref(), Some("own"));
+        assert!(denied(select("none.example", Some("own"), other)));
+        assert!(denied(select("none.example", Some("other"), own)));
+
+        // Default identity of the destination
+        assert_eq!(select("own.example", None, own).unwrap().as_deref(), Some("own"));
+        assert_eq!(select("other.example", None, other).unwrap().as_deref(), Some("other"));
+        assert!(denied(select("other.example", None, own)));
+        // Requested identity replaces the default
+        assert_eq!(select("other.example", Some("all"), own).unwrap().as_deref(), Some("all"));
+    }
+}